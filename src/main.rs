@@ -1,44 +1,153 @@
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::any::TypeId;
+use std::collections::{HashMap, VecDeque};
 use std::env;
+use std::ffi::OsString;
 use std::fs::{create_dir_all, OpenOptions};
-use std::io::{self, Read, Write};
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::os::unix::process::ExitStatusExt;
 use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock, mpsc};
 use std::thread;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use wait_timeout::ChildExt;
 
+/// Set by `--dump-last-message` to silence ralph's own status lines on stdout.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// `println!` for `[ralph]` status lines; silent when `QUIET` is set.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            println!($($arg)*);
+        }
+    };
+}
+
 #[derive(Parser, Debug)]
 #[command(name = "ralph", about = "Permissive Ralph loop runner")]
 struct Args {
     #[arg(long, default_value = "codex")]
     runner: String,
+    /// Script for `--runner mock` (defaults to `RALPH_MOCK_SCRIPT`).
+    #[cfg(feature = "mock-runner")]
+    #[arg(long, value_name = "PATH")]
+    mock_script: Option<PathBuf>,
     #[arg(long, default_value = "gpt-5.2-codex")]
     model: String,
+    /// Models to rotate through, one per iteration (overrides --model).
+    #[arg(long, value_delimiter = ',')]
+    models: Vec<String>,
+    /// JSON file mapping iteration numbers to `{model, reasoning_effort, runner_arg}` overrides.
+    #[arg(long, value_name = "PATH")]
+    schedule_file: Option<PathBuf>,
     #[arg(long, value_name = "EFFORT", default_value = "xhigh")]
     reasoning_effort: String,
-    #[arg(long, default_value_t = 24)]
+    /// Upper bound on iterations; the loop can stop earlier (stop token, --stop-when, errors).
+    #[arg(long, visible_alias = "max-iterations", value_name = "N", default_value_t = 24)]
     iterations: u32,
-    #[arg(long, default_value_t = 15)]
+    #[arg(long, value_name = "DURATION", default_value_t = 15, value_parser = parse_duration_secs)]
     sleep: u64,
-    #[arg(long, default_value_t = 0)]
+    /// Shorten each sleep by the time the iteration took.
+    #[arg(long)]
+    adaptive_sleep: bool,
+    /// Add a random 0..=DURATION delay to each sleep between iterations.
+    #[arg(long, value_name = "DURATION", default_value_t = 0, value_parser = parse_duration_secs)]
+    sleep_jitter: u64,
+    /// Seed for ralph's PRNG (jitter); random and printed when unset.
+    #[arg(long, value_name = "N")]
+    seed: Option<u64>,
+    /// Cool down this long after consecutive failed, rate-limited iterations, doubling per
+    /// hit (0 disables; off by default).
+    #[arg(long, value_name = "DURATION", default_value_t = 0, value_parser = parse_duration_secs)]
+    rate_limit_cooldown: u64,
+    #[arg(long, visible_alias = "max-duration", value_name = "DURATION", default_value_t = 0, value_parser = parse_duration_secs)]
     max_seconds: u64,
     #[arg(long)]
     prompt_template: Option<PathBuf>,
+    /// Fetch the prompt template from URL (cached in ralph/remote-template.md).
+    #[arg(long, value_name = "URL", conflicts_with = "prompt_template")]
+    prompt_template_url: Option<String>,
+    /// Fail on an empty prompt template instead of regenerating it.
+    #[arg(long)]
+    strict: bool,
+    /// Re-read the prompt template at the start of every iteration.
+    #[arg(long)]
+    reload_template: bool,
+    /// Warn when the agent modifies the PRD or prompt template during an iteration.
+    #[arg(long)]
+    guard_state: bool,
+    /// Restore the prompt template if the agent modifies it.
+    #[arg(long)]
+    protect_template: bool,
     #[arg(long)]
     prd: Option<PathBuf>,
     #[arg(long)]
     progress: Option<PathBuf>,
+    /// Leave the PRD reference out of the prompt.
+    #[arg(long)]
+    no_prd_ref: bool,
+    /// Leave the progress log reference out of the prompt.
+    #[arg(long)]
+    no_progress_ref: bool,
+    /// Fail on template placeholders other than `{{PRD}}` and `{{PROGRESS}}`.
+    #[arg(long)]
+    validate_template: bool,
+    /// Warn when the prompt never mentions the stop token.
+    #[arg(long)]
+    prompt_lint: bool,
+    /// Report each template placeholder substitution on stderr.
+    #[arg(long)]
+    prompt_debug: bool,
+    /// Strip comments and blank lines from files included in the context.
+    #[arg(long)]
+    prompt_compress: bool,
     #[arg(long)]
     log: Option<PathBuf>,
     #[arg(long)]
     no_log: bool,
-    #[arg(long, default_value = "__RALPH_DONE__")]
+    /// Mask secrets in the log and context. A PATTERN with `*` (any run) or `?` (one character)
+    /// masks whole whitespace-separated words it matches, e.g. `password=*`; any other PATTERN
+    /// adds to the built-in token prefixes such as `sk-` and `ghp_`.
+    #[arg(long, value_name = "PATTERN", num_args = 0.., value_delimiter = ',')]
+    redact_log: Option<Vec<String>>,
+    /// Run the first N iterations without logging them.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    warmup_iterations: u32,
+    #[arg(long, visible_alias = "done-token", default_value = "__RALPH_DONE__")]
     stop_token: String,
-    #[arg(long, default_value = "-p")]
-    prompt_flag: String,
+    /// File with additional stop tokens, one per line (`#` comments allowed).
+    #[arg(long, value_name = "PATH")]
+    stop_token_file_list: Option<PathBuf>,
+    /// Stop with exit code 2 when the runner prints this token.
+    #[arg(long)]
+    blocked_token: Option<String>,
+    /// Exit with code 3 if the run ends without the stop token appearing.
+    #[arg(long)]
+    require_stop_token: bool,
+    /// Treat an iteration with no stdout as a failure.
+    #[arg(long)]
+    fail_on_empty_output: bool,
+    /// Runner output stream(s) to search for the stop token.
+    #[arg(long, value_enum, value_name = "STREAM", default_value_t = StopTokenScan::Stdout)]
+    stop_token_scan: StopTokenScan,
+    /// Remove the stop token from displayed and logged output.
+    #[arg(long)]
+    stop_token_strip: bool,
+    /// Also drop everything after the first stop token.
+    #[arg(long, requires = "stop_token_strip")]
+    stop_token_strip_trailing: bool,
+    #[arg(long, default_value = "-p", action = clap::ArgAction::Append)]
+    prompt_flag: Vec<String>,
     #[arg(long)]
     extra: Option<String>,
     #[arg(long)]
@@ -49,46 +158,508 @@ struct Args {
     specialization: Option<String>,
     #[arg(long, default_value_t = true)]
     codex_json: bool,
-    #[arg(long, default_value_t = 0)]
-    runner_timeout: u64,
+    /// Stop when a codex JSON event has FIELD equal to VALUE (e.g. `msg.type=task_complete`).
+    #[arg(long, value_name = "FIELD=VALUE", value_parser = parse_json_stop_field)]
+    json_stop_field: Option<(String, String)>,
+    /// Pass the prompt to codex on stdin or as a temp file.
+    #[arg(long, value_enum, default_value_t = PromptVia::Stdin)]
+    codex_prompt_via: PromptVia,
+    /// Kill the runner after this long; defaults to 2h for codex and sdk, 1h for claude and 30m
+    /// for other runners (0 or `none` disables the timeout).
+    #[arg(long, value_name = "DURATION", value_parser = parse_runner_timeout)]
+    runner_timeout: Option<u64>,
+    /// On timeout, send SIGTERM and wait this long before SIGKILL (0 kills immediately).
+    #[arg(long, value_name = "DURATION", default_value_t = 0, value_parser = parse_duration_secs)]
+    timeout_kill_grace: u64,
+    /// Retry an iteration that fails, times out, is killed or (with
+    /// --fail-on-empty-output) prints nothing, up to N times.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    max_retries: u32,
+    /// Stop once codex-reported token usage reaches N (0 disables).
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    max_total_tokens: u64,
+    /// Warn and mark the log when an iteration takes at least DURATION (0 disables).
+    #[arg(long, value_name = "DURATION", default_value_t = 0, value_parser = parse_duration_secs)]
+    warn_slow_iteration: u64,
+    /// Abort when the ralph/ filesystem has fewer free bytes than this (0 disables).
+    #[arg(long, value_name = "BYTES", default_value_t = 0)]
+    min_free_space: u64,
+    /// Note appended to the prompt on retry attempts (empty disables).
+    #[arg(long, value_name = "TEXT", default_value = "The previous attempt failed; proceed carefully and incrementally.")]
+    retry_note: String,
     #[arg(long, default_value_t = 24)]
     sdk_max_turns: u32,
     #[arg(long, default_value_t = true)]
     ensure_mcp: bool,
+    /// Never touch codex's config.toml (by default the OpenAI docs MCP server is added once if missing).
+    #[arg(long)]
+    no_ensure_mcp: bool,
     #[arg(long)]
     context_log: Option<PathBuf>,
+    /// Reuse a repository context snapshot newer than SECONDS (0 disables).
+    #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+    context_cache_ttl: u64,
+    /// Per-file context size in bytes, or a multiplier of the defaults (e.g. `2x`).
+    #[arg(long, value_name = "BYTES|FACTORx", value_parser = parse_context_limit)]
+    context_file_limit: Option<ContextLimit>,
+    /// Files to include as the readme (`*`/`?` wildcards, e.g. `docs/*.rst`); repeatable.
+    #[arg(long, value_name = "GLOB", action = clap::ArgAction::Append)]
+    context_readme_glob: Vec<String>,
+    /// Omit binary context files instead of including a placeholder for them.
+    #[arg(long)]
+    context_binary_skip: bool,
+    /// Limit the recent-commits context to authors matching PATTERN (passed to `git log --author`).
+    #[arg(long, value_name = "PATTERN", value_parser = clap::builder::NonEmptyStringValueParser::new())]
+    context_git_author: Option<String>,
+    /// Add environment variable VAR to the context (repeatable; secrets and URL passwords masked).
+    #[arg(long, value_name = "VAR", action = clap::ArgAction::Append)]
+    context_env: Vec<String>,
+    /// Also add the --context-env section to every iteration prompt.
+    #[arg(long, requires = "context_env")]
+    prompt_context_env: bool,
+    /// Comma-separated context sections to put first; the rest keep their default order.
+    #[arg(long, value_name = "SECTIONS", value_delimiter = ',', value_parser = clap::builder::PossibleValuesParser::new(CONTEXT_SECTIONS))]
+    context_priority: Vec<String>,
+    /// Skip context commands (git, rg) that run longer than DURATION (0 disables).
+    #[arg(long, value_name = "DURATION", default_value_t = 0, value_parser = parse_duration_secs)]
+    context_command_timeout: u64,
+    /// Keep only the N highest-churn files in the diffstat context.
+    #[arg(long, value_name = "N")]
+    diff_context_max_files: Option<usize>,
     #[arg(long, default_value_t = false)]
     infer_only: bool,
+    /// JSON schema the inferred goal must satisfy.
+    #[arg(long, value_name = "PATH")]
+    infer_schema: Option<PathBuf>,
+    /// Ask for N goal proposals and choose one interactively.
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    infer_candidates: u32,
+    /// Let uncommitted diffs inform the inferred goal, not just the next action.
+    #[arg(long)]
+    infer_include_diff: bool,
     #[arg(long, default_value_t = false)]
     list_mcp: bool,
+    /// With --list-mcp, check that each HTTP server is reachable.
+    #[arg(long, requires = "list_mcp")]
+    check: bool,
+    /// Print the repository context and exit.
+    #[arg(long, default_value_t = false)]
+    dump_context: bool,
+    /// Show ralph's state files, last run and integrations for this repo, then exit.
+    #[arg(long, default_value_t = false)]
+    status: bool,
+    /// Read flags from a flat TOML file (keys per --config-schema); command-line values win.
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Print the JSON schema of the --config file and exit.
+    #[arg(long, default_value_t = false)]
+    config_schema: bool,
+    /// Summarize a past run from its log (text or JSONL) and exit.
+    #[arg(long, value_name = "PATH")]
+    replay_log: Option<PathBuf>,
+    /// Run the loop in a child ralph and restart it if it crashes.
+    #[arg(long, conflicts_with = "worktree")]
+    supervise: bool,
+    /// File where a supervised child records its completed iterations and tokens used.
+    #[arg(long, value_name = "PATH", hide = true)]
+    supervise_state: Option<PathBuf>,
+    /// Iteration number a restarted supervised child continues from.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        hide = true,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    start_iteration: u32,
+    /// Tokens used by earlier supervised children, counted toward --max-total-tokens.
+    #[arg(long, value_name = "N", default_value_t = 0, hide = true)]
+    tokens_used: u64,
+    /// Give up after the supervised child has been restarted N times.
+    #[arg(long, value_name = "N", default_value_t = 3, requires = "supervise")]
+    supervise_max_restarts: u32,
+    /// Run a single iteration (same as `--iterations 1`).
+    #[arg(long)]
+    once: bool,
+    /// With --once, print only the agent's final message on stdout.
+    #[arg(long, requires = "once", conflicts_with = "supervise")]
+    dump_last_message: bool,
     #[arg(long, action = clap::ArgAction::Append)]
     runner_arg: Vec<String>,
+    /// Set KEY=VALUE in the runner's environment (repeatable).
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_env_pair, action = clap::ArgAction::Append)]
+    runner_env: Vec<(String, String)>,
+    /// Python command for the SDK runner (default: `uv run python`, else python3/python).
+    #[arg(long, value_name = "CMD")]
+    sdk_python: Option<String>,
     #[arg(long)]
     resume: bool,
     #[arg(long)]
     resume_id: Option<String>,
+    /// Resume this repo's latest codex session from the past week, if any.
+    #[arg(long)]
+    resume_if_session_exists: bool,
+    /// Prepend the previous run's outcome to the first iteration's prompt.
+    #[arg(long)]
+    resume_context: bool,
+    /// Ask the agent for a plan (ralph/plan.md) before the first iteration.
+    #[arg(long)]
+    plan: bool,
+    /// Point every iteration's prompt at the plan.
+    #[arg(long, requires = "plan")]
+    plan_in_prompt: bool,
+    /// Append the run summary to the progress log when the run ends.
+    #[arg(long)]
+    append_run_summary: bool,
+    /// Refuse to start when `git status --short` shows changes outside ralph/.
+    #[arg(long)]
+    abort_on_uncommitted_at_start: bool,
+    /// Override --abort-on-uncommitted-at-start.
+    #[arg(long)]
+    allow_dirty: bool,
+    /// Progress log format; `jsonl` appends one JSON entry per iteration.
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = ProgressFormat::Text)]
+    progress_format: ProgressFormat,
+    /// Also write a human-readable ralph/run-summary.md with `markdown`.
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = SummaryFormat::Json)]
+    summary_format: SummaryFormat,
     #[arg(long)]
     full_auto: bool,
     #[arg(long)]
     no_yolo: bool,
+    /// Codex sandbox mode, passed as `--sandbox` instead of bypassing the sandbox.
+    #[arg(long, value_enum, value_name = "MODE")]
+    codex_sandbox_mode: Option<CodexSandbox>,
+    /// Codex config profile to pass as `--profile`.
+    #[arg(long, value_name = "NAME", value_parser = clap::builder::NonEmptyStringValueParser::new())]
+    codex_profile: Option<String>,
+    /// Enable a codex tool (`NAME`) or disable it (`NAME=false`) via `-c tools.NAME=...`.
+    #[arg(long, value_name = "NAME[=BOOL]", action = clap::ArgAction::Append)]
+    codex_tool: Vec<String>,
+    /// Codex home directory (overrides CODEX_HOME; defaults to ~/.codex).
+    #[arg(long, value_name = "DIR")]
+    codex_home: Option<PathBuf>,
+    /// Keep the prompt and last-message temp files instead of deleting them.
+    #[arg(long)]
+    keep_temp: bool,
+    /// Print the first N lines of each iteration's prompt (0 disables).
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    prompt_preview_lines: usize,
+    /// Keep at most N distinct `@path` file references in the prompt (0 disables).
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    prompt_max_files_attached: usize,
+    /// Include the last N iteration outputs in the prompt (0 disables).
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    max_prompt_history: usize,
+    /// Unix socket to stream JSON progress events to, one per line.
+    #[arg(long, value_name = "PATH")]
+    event_socket: Option<PathBuf>,
+    /// Check out branch NAME, creating it if needed, before the run.
+    #[arg(long, value_name = "NAME")]
+    git_branch: Option<String>,
+    /// Switch back to the original branch when the run ends.
+    #[arg(long, requires = "git_branch")]
+    return_to_branch: bool,
+    /// Stash uncommitted changes before switching to --git-branch.
+    #[arg(long)]
+    stash: bool,
+    /// Commit tracked changes as a baseline before the first iteration.
+    #[arg(long)]
+    preflight_commit: bool,
+    /// Run in a git worktree at PATH on branch `ralph/<name>` (reused if present).
+    #[arg(long, value_name = "PATH")]
+    worktree: Option<PathBuf>,
+    /// Ref to start a new worktree's branch from (default: HEAD).
+    #[arg(long, value_name = "REF", requires = "worktree")]
+    worktree_base: Option<String>,
+    /// Remove the worktree when the run ends, unless it has uncommitted work.
+    #[arg(long, requires = "worktree")]
+    remove_worktree: bool,
+    /// Use the files in DIR, in name order, as one prompt per iteration.
+    #[arg(long, value_name = "DIR")]
+    task_dir: Option<PathBuf>,
+    /// Use a Linear issue as the prompt.
+    #[arg(long, value_name = "ISSUE", conflicts_with = "task_dir")]
+    prompt_from_issue: Option<String>,
+    /// Stop expression over token, iterations:N, seconds:N, idle:N and file:PATH, joined
+    /// with and/or and parentheses. `cost:` is not supported.
+    #[arg(long, value_name = "EXPR")]
+    stop_when: Option<String>,
+    /// Stop as soon as any model emits the stop token. This overrides --stop-when: the token
+    /// ends the run even if the expression is not met, as if it read `token or (EXPR)`.
+    #[arg(long)]
+    stop_after_first_success: bool,
+    /// Post the run review as a comment on a Linear issue or an update on a project.
+    #[arg(long, value_name = "ISSUE|PROJECT")]
+    summary_to_linear: Option<String>,
+    /// Shell command to run once after the loop, if the run succeeded.
+    #[arg(long, value_name = "CMD")]
+    post_run_command: Option<String>,
+    /// Run --post-run-command even when the run fails.
+    #[arg(long, requires = "post_run_command")]
+    post_run_always: bool,
+    /// Extra HTTP header for Linear requests (repeatable).
+    #[arg(long, value_name = "KEY:VALUE", value_parser = parse_header, action = clap::ArgAction::Append)]
+    linear_header: Vec<(String, String)>,
+    /// Limit Linear context to this project.
+    #[arg(long, value_name = "NAME|ID")]
+    linear_project: Option<String>,
+    /// Use the Linear token for workspace NAME (e.g. `LINEAR_API_KEY_<NAME>`).
+    #[arg(long, value_name = "NAME")]
+    linear_workspace: Option<String>,
+    /// Proxy URL for Linear, GitHub and other HTTP requests (default: HTTPS_PROXY).
+    #[arg(long, value_name = "URL", value_parser = parse_proxy_url)]
+    http_proxy: Option<String>,
+    /// Only pull Linear issues/documents updated within N days (0 disables).
+    #[arg(long, value_name = "N", default_value_t = 30)]
+    linear_recent_days: u32,
+    /// Put raw Linear JSON in the context instead of one-line summaries.
+    #[arg(long)]
+    linear_output_raw: bool,
+    /// Create a Linear issue for each unchecked PRD task, then exit.
+    #[arg(long, requires = "linear_team")]
+    linear_create_issues_from_tasks: bool,
+    /// Linear team for --linear-create-issues-from-tasks.
+    #[arg(long, value_name = "KEY|ID")]
+    linear_team: Option<String>,
+    /// Abort a merge the agent leaves conflicted and refresh the context.
+    #[arg(long)]
+    recover_on_conflict: bool,
+    /// Command to recover from merge conflicts (default: `git merge --abort`).
+    #[arg(long, value_name = "CMD")]
+    recover_command: Option<String>,
 }
 
 fn env_or_path(name: &str, fallback: PathBuf) -> PathBuf {
     env::var(name).map(PathBuf::from).unwrap_or(fallback)
 }
 
-fn load_prompt(template_path: &Path, prd_path: &Path, progress_path: &Path) -> io::Result<String> {
+/// Resolves the prompt template, PRD, progress and log paths under `cwd`. With
+/// `--prompt-template-url` the template path is where the fetched copy is cached.
+fn state_paths(args: &Args, cwd: &Path) -> [PathBuf; 4] {
+    let template = if args.prompt_template_url.is_some() {
+        cwd.join("ralph/remote-template.md")
+    } else {
+        let template = args.prompt_template.clone().unwrap_or_else(|| {
+            env_or_path("RALPH_PROMPT_TEMPLATE", cwd.join("ralph/prompt-template.md"))
+        });
+        resolve_template_path(&template, &args.runner)
+    };
+    let prd = args
+        .prd
+        .clone()
+        .unwrap_or_else(|| env_or_path("RALPH_PRD", cwd.join("ralph/PRD.md")));
+    let progress = args
+        .progress
+        .clone()
+        .unwrap_or_else(|| env_or_path("RALPH_PROGRESS", cwd.join("ralph/progress.txt")));
+    let log = args
+        .log
+        .clone()
+        .unwrap_or_else(|| env_or_path("RALPH_LOG", cwd.join("ralph/overnight.log")));
+    [template, prd, progress, log]
+}
+
+fn resolve_template_path(template: &Path, runner: &str) -> PathBuf {
+    if !template.is_dir() {
+        return template.to_path_buf();
+    }
+    let specific = template.join(format!("{runner}.md"));
+    if specific.is_file() {
+        return specific;
+    }
+    template.join("default.md")
+}
+
+/// Applies `--http-proxy`, falling back to `HTTPS_PROXY`/`https_proxy`, to every HTTP client.
+fn http_client(proxy: Option<&str>) -> reqwest::blocking::ClientBuilder {
+    let builder = reqwest::blocking::Client::builder();
+    let proxy = proxy.map(str::to_string).or_else(|| {
+        ["HTTPS_PROXY", "https_proxy"]
+            .iter()
+            .find_map(|name| env::var(name).ok().filter(|value| !value.trim().is_empty()))
+    });
+    match proxy.as_deref().map(reqwest::Proxy::all) {
+        Some(Ok(proxy)) => builder.proxy(proxy),
+        Some(Err(err)) => {
+            eprintln!("[ralph] ignoring invalid HTTP proxy: {err}");
+            builder
+        }
+        None => builder,
+    }
+}
+
+fn parse_proxy_url(value: &str) -> Result<String, String> {
+    reqwest::Proxy::all(value)
+        .map(|_| value.to_string())
+        .map_err(|err| err.to_string())
+}
+
+fn fetch_remote_template(url: &str, cache_path: &Path, proxy: Option<&str>) -> io::Result<PathBuf> {
+    let fetched = http_client(proxy)
+        .timeout(Duration::from_secs(30))
+        .build()
+        .and_then(|client| client.get(url).send())
+        .and_then(|resp| resp.error_for_status())
+        .and_then(|resp| resp.text());
+    match fetched {
+        Ok(template) => {
+            if let Some(parent) = cache_path.parent() {
+                create_dir_all(parent)?;
+            }
+            std::fs::write(cache_path, template)?;
+            status!("[ralph] fetched prompt template from {url}");
+        }
+        Err(err) if cache_path.is_file() => {
+            eprintln!(
+                "[ralph] could not fetch {url} ({err}); using cached {}",
+                cache_path.display()
+            );
+        }
+        Err(err) => {
+            return Err(io::Error::other(format!(
+                "Failed to fetch prompt template {url}: {err}"
+            )));
+        }
+    }
+    Ok(cache_path.to_path_buf())
+}
+
+fn substitute_file_ref(template: &str, placeholder: &str, path: Option<&Path>) -> String {
+    match path {
+        Some(path) => template.replace(placeholder, &format!("@{}", path.display())),
+        None => template
+            .split_inclusive('\n')
+            .filter(|line| !line.contains(placeholder))
+            .collect(),
+    }
+}
+
+fn debug_substitution(template: &str, placeholder: &str, path: Option<&Path>) {
+    let count = template.matches(placeholder).count();
+    match path {
+        _ if count == 0 => eprintln!("[ralph] prompt-debug: {placeholder} not present"),
+        Some(path) => eprintln!(
+            "[ralph] prompt-debug: {placeholder} -> @{} ({count}x)",
+            path.display()
+        ),
+        None => eprintln!("[ralph] prompt-debug: {placeholder} line removed ({count}x)"),
+    }
+}
+
+fn render_prompt(
+    template: &str,
+    prd_path: Option<&Path>,
+    progress_path: Option<&Path>,
+    debug: bool,
+) -> String {
+    if debug {
+        debug_substitution(template, "{{PRD}}", prd_path);
+        debug_substitution(template, "{{PROGRESS}}", progress_path);
+    }
+    let prompt = substitute_file_ref(template, "{{PRD}}", prd_path);
+    substitute_file_ref(&prompt, "{{PROGRESS}}", progress_path)
+}
+
+fn load_prompt(
+    template_path: &Path,
+    prd_path: Option<&Path>,
+    progress_path: Option<&Path>,
+    debug: bool,
+) -> io::Result<String> {
     let template = std::fs::read_to_string(template_path)?;
-    let prd_ref = format!("@{}", prd_path.display());
-    let progress_ref = format!("@{}", progress_path.display());
-    Ok(template
-        .replace("{{PRD}}", &prd_ref)
-        .replace("{{PROGRESS}}", &progress_ref))
+    if debug {
+        eprintln!("[ralph] prompt-debug: rendering {}", template_path.display());
+    }
+    Ok(render_prompt(&template, prd_path, progress_path, debug))
+}
+
+fn print_prompt_preview(prompt: &str, max_lines: usize) {
+    let total = prompt.lines().count();
+    for line in prompt.lines().take(max_lines) {
+        status!("[ralph] prompt> {line}");
+    }
+    if total > max_lines {
+        status!("[ralph] prompt> … ({} more lines)", total - max_lines);
+    }
+}
+
+fn list_task_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with('.'));
+        if path.is_file() && !hidden {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn with_extra(prompt: String, extra: Option<&str>) -> String {
+    match extra {
+        Some(extra) if !extra.trim().is_empty() => format!("{extra}\n\n{prompt}"),
+        _ => prompt,
+    }
+}
+
+fn with_history(prompt: &str, history: &VecDeque<(u32, String)>) -> String {
+    if history.is_empty() {
+        return prompt.to_string();
+    }
+    let mut section = vec!["## Previous iterations (most recent last)".to_string()];
+    for (iteration, message) in history {
+        section.push(format!("### Iteration {iteration}\n{message}"));
+    }
+    format!("{}\n\n{prompt}", section.join("\n\n"))
+}
+
+fn template_placeholders(template: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            break;
+        };
+        let name = after[..end].trim().to_string();
+        if !names.contains(&name) {
+            names.push(name);
+        }
+        rest = &after[end + 2..];
+    }
+    names
+}
+
+fn validate_template(path: &Path) -> io::Result<()> {
+    let known = ["PRD", "PROGRESS"];
+    let template = std::fs::read_to_string(path)?;
+    let unknown: Vec<String> = template_placeholders(&template)
+        .into_iter()
+        .filter(|name| !known.contains(&name.as_str()))
+        .map(|name| format!("{{{{{name}}}}}"))
+        .collect();
+    if !unknown.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Template {} has unresolved placeholders: {} (known: {{{{PRD}}}}, {{{{PROGRESS}}}})",
+                path.display(),
+                unknown.join(", ")
+            ),
+        ));
+    }
+    status!("[ralph] template ok: {}", path.display());
+    Ok(())
 }
 
 fn prompt_for_goal(repo_name: &str) -> io::Result<String> {
     loop {
-        println!(
+        status!(
             "[ralph] No prompt template found. What's the goal for this repo ({repo_name})?"
         );
         print!("[ralph] goal> ");
@@ -99,13 +670,13 @@ fn prompt_for_goal(repo_name: &str) -> io::Result<String> {
         if !trimmed.is_empty() {
             return Ok(trimmed);
         }
-        println!("[ralph] Goal cannot be empty.");
+        status!("[ralph] Goal cannot be empty.");
     }
 }
 
 fn prompt_for_next_action() -> io::Result<String> {
     loop {
-        println!("[ralph] What's the immediate next action you want taken?");
+        status!("[ralph] What's the immediate next action you want taken?");
         print!("[ralph] next action> ");
         io::stdout().flush()?;
         let mut input = String::new();
@@ -114,7 +685,7 @@ fn prompt_for_next_action() -> io::Result<String> {
         if !trimmed.is_empty() {
             return Ok(trimmed);
         }
-        println!("[ralph] Next action cannot be empty.");
+        status!("[ralph] Next action cannot be empty.");
     }
 }
 
@@ -184,15 +755,223 @@ fn prompt_yes_no(message: &str) -> io::Result<bool> {
 }
 
 fn run_command_output(cmd: &str, args: &[&str], cwd: &Path) -> Option<String> {
-    let out = Command::new(cmd).args(args).current_dir(cwd).output().ok()?;
-    if !out.status.success() {
+    run_command_output_timeout(cmd, args, cwd, None).ok().flatten()
+}
+
+fn free_space_bytes(path: &Path, cwd: &Path) -> Option<u64> {
+    // df needs an existing path; the state directory may not exist yet.
+    let path = cwd.join(path);
+    let existing = path.ancestors().find(|ancestor| ancestor.exists())?;
+    let target = existing.to_string_lossy();
+    let output = run_command_output("df", &["-Pk", target.as_ref()], cwd)?;
+    let line = output.lines().nth(1)?;
+    let available_kb: u64 = line.split_whitespace().nth(3)?.parse().ok()?;
+    Some(available_kb * 1024)
+}
+
+fn low_disk_space(path: &Path, cwd: &Path, min_free: u64) -> Option<String> {
+    if min_free == 0 {
+        return None;
+    }
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    let Some(free) = free_space_bytes(path, cwd) else {
+        if !WARNED.swap(true, Ordering::Relaxed) {
+            eprintln!(
+                "[ralph] warning: could not check free space for {}; --min-free-space is not enforced",
+                path.display()
+            );
+        }
         return None;
+    };
+    (free < min_free).then(|| {
+        format!(
+            "low disk space on {} ({free} bytes free, need {min_free})",
+            path.display()
+        )
+    })
+}
+
+fn run_command_output_timeout(
+    cmd: &str,
+    args: &[&str],
+    cwd: &Path,
+    timeout: Option<Duration>,
+) -> io::Result<Option<String>> {
+    let mut command = Command::new(cmd);
+    command.args(args).current_dir(cwd);
+    let out = run_process_with_timeout(command, ProcessInput::Empty, timeout, true, false, None)?;
+    if !out.status.success() {
+        return Ok(None);
     }
     let text = String::from_utf8_lossy(&out.stdout).trim().to_string();
-    if text.is_empty() {
-        None
+    Ok(if text.is_empty() { None } else { Some(text) })
+}
+
+fn run_command_success(cmd: &str, args: &[&str], cwd: &Path) -> bool {
+    Command::new(cmd)
+        .args(args)
+        .current_dir(cwd)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+fn current_git_branch(cwd: &Path) -> Option<String> {
+    run_command_output("git", &["rev-parse", "--abbrev-ref", "HEAD"], cwd)
+}
+
+fn git_has_uncommitted_changes(cwd: &Path) -> bool {
+    run_command_output(
+        "git",
+        &["status", "--porcelain", "--untracked-files=no"],
+        cwd,
+    )
+    .is_some()
+}
+
+/// `git status --short` entries outside ralph's own `ralph/` state directory.
+fn dirty_paths_at_start(cwd: &Path) -> Vec<String> {
+    run_command_output("git", &["status", "--short"], cwd)
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| {
+            let path = line.trim_start().split_once(' ').map(|(_, path)| path.trim_start());
+            !path.unwrap_or_default().starts_with("ralph/")
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+/// Checks out `branch`, creating it if needed; returns whether changes were stashed.
+fn switch_to_branch(cwd: &Path, branch: &str, stash: bool) -> io::Result<bool> {
+    let stashed = git_has_uncommitted_changes(cwd);
+    if stashed {
+        if !stash {
+            return Err(io::Error::other(format!(
+                "Uncommitted changes present; commit them or pass --stash before switching to {branch}"
+            )));
+        }
+        let message = format!("ralph: before switching to {branch}");
+        if !run_command_success("git", &["stash", "push", "-m", &message], cwd) {
+            return Err(io::Error::other("git stash failed"));
+        }
+        status!("[ralph] stashed uncommitted changes");
+    }
+    let branch_ref = format!("refs/heads/{branch}");
+    let exists = run_command_success(
+        "git",
+        &["rev-parse", "--verify", "--quiet", &branch_ref],
+        cwd,
+    );
+    let switched = if exists {
+        run_command_success("git", &["checkout", branch], cwd)
+    } else {
+        run_command_success("git", &["checkout", "-b", branch], cwd)
+    };
+    if !switched {
+        return Err(io::Error::other(format!(
+            "Failed to check out branch {branch}"
+        )));
+    }
+    status!("[ralph] working on branch {branch}");
+    Ok(stashed)
+}
+
+fn preflight_commit(cwd: &Path) -> io::Result<Option<String>> {
+    if git_has_uncommitted_changes(cwd) {
+        if !run_command_success("git", &["add", "-u"], cwd)
+            || !run_command_success("git", &["commit", "-m", "ralph baseline"], cwd)
+        {
+            return Err(io::Error::other("Failed to commit ralph baseline"));
+        }
+        status!("[ralph] committed tracked changes as ralph baseline");
+    }
+    Ok(run_command_output("git", &["rev-parse", "HEAD"], cwd))
+}
+
+struct BranchRestore {
+    cwd: PathBuf,
+    branch: String,
+    pop_stash: bool,
+}
+
+impl Drop for BranchRestore {
+    fn drop(&mut self) {
+        if !run_command_success("git", &["checkout", &self.branch], &self.cwd) {
+            eprintln!("[ralph] failed to return to branch {}", self.branch);
+            if self.pop_stash {
+                eprintln!("[ralph] stashed changes remain in `git stash list`");
+            }
+            return;
+        }
+        status!("[ralph] returned to branch {}", self.branch);
+        if self.pop_stash {
+            if run_command_success("git", &["stash", "pop"], &self.cwd) {
+                status!("[ralph] restored stashed changes");
+            } else {
+                eprintln!("[ralph] warning: git stash pop failed; resolve conflicts and `git stash drop`");
+            }
+        }
+    }
+}
+
+fn create_worktree(repo: &Path, path: &Path, base: Option<&str>) -> io::Result<PathBuf> {
+    let path = repo.join(path);
+    if path.join(".git").exists() {
+        status!("[ralph] reusing worktree {}", path.display());
+        return path.canonicalize();
+    }
+    let target = path.to_string_lossy();
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "worktree".to_string());
+    let branch = format!("ralph/{name}");
+    let base = base.unwrap_or("HEAD");
+    let branch_exists = run_command_success(
+        "git",
+        &["rev-parse", "--verify", "--quiet", &format!("refs/heads/{branch}")],
+        repo,
+    );
+    let created = if branch_exists {
+        run_command_success("git", &["worktree", "add", &target, &branch], repo)
     } else {
-        Some(text)
+        run_command_success("git", &["worktree", "add", "-b", &branch, &target, base], repo)
+    };
+    if !created {
+        return Err(io::Error::other(format!(
+            "Failed to create worktree {target} on branch {branch}"
+        )));
+    }
+    let path = path.canonicalize()?;
+    if branch_exists {
+        status!("[ralph] created worktree {} on existing branch {branch}", path.display());
+    } else {
+        status!("[ralph] created worktree {} on new branch {branch} from {base}", path.display());
+    }
+    Ok(path)
+}
+
+struct WorktreeCleanup {
+    repo: PathBuf,
+    path: PathBuf,
+}
+
+impl Drop for WorktreeCleanup {
+    fn drop(&mut self) {
+        let target = self.path.to_string_lossy();
+        // Only ralph's own state files may be discarded; agent work keeps the worktree.
+        if !dirty_paths_at_start(&self.path).is_empty() {
+            eprintln!("[ralph] worktree {target} has uncommitted changes; not removing it");
+            return;
+        }
+        if run_command_success("git", &["worktree", "remove", "--force", &target], &self.repo) {
+            status!("[ralph] removed worktree {target}");
+        } else {
+            eprintln!("[ralph] failed to remove worktree {target}");
+        }
     }
 }
 
@@ -299,17 +1078,48 @@ fn filter_diffstat_for_context(diffstat: &str) -> (String, String) {
         if trimmed.is_empty() {
             continue;
         }
-        if let Some((path, _rest)) = trimmed.split_once(" |") {
-            if is_noise_path_rename(path.trim()) {
-                ignored.push(path.trim().to_string());
-                continue;
-            }
+        if let Some((path, _rest)) = trimmed.split_once(" |")
+            && is_noise_path_rename(path.trim())
+        {
+            ignored.push(path.trim().to_string());
+            continue;
         }
         kept.push(line.to_string());
     }
     (kept.join("\n"), ignored.join("\n"))
 }
 
+fn limit_diffstat_files(diffstat: &str, max_files: usize) -> String {
+    let mut files = Vec::new();
+    let mut totals = Vec::new();
+    for line in diffstat.lines() {
+        match line.split_once(" |") {
+            Some((_, rest)) => {
+                let churn = rest
+                    .split_whitespace()
+                    .next()
+                    .and_then(|count| count.parse::<usize>().ok())
+                    .unwrap_or(0);
+                files.push((churn, line));
+            }
+            None => totals.push(line),
+        }
+    }
+    if files.len() <= max_files {
+        return diffstat.to_string();
+    }
+    let omitted = files.len() - max_files;
+    files.sort_by_key(|(churn, _)| std::cmp::Reverse(*churn));
+    let mut lines: Vec<String> = files
+        .iter()
+        .take(max_files)
+        .map(|(_, line)| line.to_string())
+        .collect();
+    lines.push(format!(" … {omitted} more files with smaller changes"));
+    lines.extend(totals.iter().map(|line| line.to_string()));
+    lines.join("\n")
+}
+
 fn summarize_active_paths(diffstat: &str) -> Option<String> {
     let mut counts: HashMap<String, usize> = HashMap::new();
     for line in diffstat.lines() {
@@ -337,7 +1147,7 @@ fn summarize_active_paths(diffstat: &str) -> Option<String> {
         return None;
     }
     let mut items: Vec<(String, usize)> = counts.into_iter().collect();
-    items.sort_by(|a, b| b.1.cmp(&a.1));
+    items.sort_by_key(|item| std::cmp::Reverse(item.1));
     let summary: Vec<String> = items
         .into_iter()
         .take(5)
@@ -362,7 +1172,90 @@ fn append_context(lines: &mut Vec<String>, label: &str, value: Option<String>, l
     }
 }
 
-fn linear_token() -> Option<String> {
+/// Replaces the password in URL userinfo (`scheme://user:pw@host`) with `***`.
+fn mask_url_userinfo(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(scheme_end) = rest.find("://") {
+        let (head, tail) = rest.split_at(scheme_end + 3);
+        out.push_str(head);
+        let authority_end = tail.find(['/', '?', '#', ' ']).unwrap_or(tail.len());
+        match tail[..authority_end].rfind('@') {
+            Some(at) => {
+                match tail[..at].split_once(':') {
+                    Some((user, _)) => out.push_str(&format!("{user}:***")),
+                    None => out.push_str(&tail[..at]),
+                }
+                rest = &tail[at..];
+            }
+            None => rest = tail,
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// `VAR=value` lines for the selected environment variables. Values of variables named
+/// like credentials are withheld; others have token prefixes (including `--redact-log`
+/// prefixes) and URL passwords masked.
+fn env_context(vars: &[String], redact_prefixes: &[String]) -> Option<String> {
+    if vars.is_empty() {
+        return None;
+    }
+    let lines: Vec<String> = vars
+        .iter()
+        .map(|name| {
+            let upper = name.to_ascii_uppercase();
+            let value = match env::var(name) {
+                Err(_) => "(unset)".to_string(),
+                Ok(_)
+                    if [
+                        "TOKEN",
+                        "SECRET",
+                        "PASSWORD",
+                        "PASSWD",
+                        "KEY",
+                        "CREDENTIAL",
+                        "AUTH",
+                        "PRIVATE",
+                        "COOKIE",
+                        "SESSION",
+                    ]
+                    .iter()
+                    .any(|marker| upper.contains(marker)) =>
+                {
+                    "[REDACTED]".to_string()
+                }
+                Ok(value) => {
+                    let redacted = redact_secrets(value.as_bytes(), redact_prefixes);
+                    mask_url_userinfo(&String::from_utf8_lossy(&redacted))
+                }
+            };
+            format!("{name}={value}")
+        })
+        .collect();
+    Some(lines.join("\n"))
+}
+
+fn linear_token(workspace: Option<&str>) -> Option<String> {
+    if let Some(workspace) = workspace {
+        let suffix: String = workspace
+            .trim()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        return ["LINEAR_API_KEY", "LINEAR_TOKEN", "LINEAR_API_TOKEN"]
+            .iter()
+            .filter_map(|name| env::var(format!("{name}_{suffix}")).ok())
+            .map(|value| value.trim().to_string())
+            .find(|value| !value.is_empty());
+    }
     for name in ["LINEAR_API_KEY", "LINEAR_TOKEN", "LINEAR_API_TOKEN"] {
         if let Ok(value) = env::var(name) {
             let trimmed = value.trim().to_string();
@@ -371,8 +1264,7 @@ fn linear_token() -> Option<String> {
             }
         }
     }
-    let home = env::var("HOME").ok()?;
-    let config = Path::new(&home).join(".codex/config.toml");
+    let config = codex_home()?.join("config.toml");
     let content = std::fs::read_to_string(config).ok()?;
     if let Some(idx) = content.find("lin_api_") {
         let tail = &content[idx..];
@@ -399,17 +1291,76 @@ fn linear_auth_header(token: &str) -> String {
     }
 }
 
-fn linear_graphql(query: &str, variables: Value) -> Option<Value> {
-    let token = linear_token()?;
-    let client = reqwest::blocking::Client::new();
+fn parse_env_pair(value: &str) -> Result<(String, String), String> {
+    let (key, val) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got {value}"))?;
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return Err(format!("invalid variable name in {value}"));
+    }
+    Ok((key.to_string(), val.to_string()))
+}
+
+fn parse_header(value: &str) -> Result<(String, String), String> {
+    let (key, val) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected KEY:VALUE, got {value}"))?;
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(format!("header name is empty in {value}"));
+    }
+    Ok((key.to_string(), val.trim().to_string()))
+}
+
+#[derive(Debug, Clone, Default)]
+struct LinearOptions {
+    headers: Vec<(String, String)>,
+    project: Option<String>,
+    workspace: Option<String>,
+    recent_days: u32,
+    raw: bool,
+    proxy: Option<String>,
+}
+
+impl LinearOptions {
+    fn from_args(args: &Args) -> Self {
+        LinearOptions {
+            headers: args.linear_header.clone(),
+            project: args.linear_project.clone(),
+            workspace: args.linear_workspace.clone(),
+            recent_days: args.linear_recent_days,
+            raw: args.linear_output_raw,
+            proxy: args.http_proxy.clone(),
+        }
+    }
+
+    fn recent_filter(&self) -> String {
+        if self.recent_days == 0 {
+            String::new()
+        } else {
+            format!(
+                ", filter: {{ updatedAt: {{ gt: \"-P{}D\" }} }}",
+                self.recent_days
+            )
+        }
+    }
+}
+
+fn linear_graphql(linear: &LinearOptions, query: &str, variables: Value) -> Option<Value> {
+    let token = linear_token(linear.workspace.as_deref())?;
+    let client = http_client(linear.proxy.as_deref()).build().ok()?;
     let payload = serde_json::json!({
         "query": query,
         "variables": variables,
     });
-    let resp = client
+    let request = client
         .post("https://api.linear.app/graphql")
         .header("Content-Type", "application/json")
-        .header("Authorization", linear_auth_header(&token))
+        .header("Authorization", linear_auth_header(&token));
+    let resp = linear
+        .headers
+        .iter()
+        .fold(request, |req, (key, value)| req.header(key.as_str(), value.as_str()))
         .json(&payload)
         .send()
         .ok()?;
@@ -424,68 +1375,551 @@ fn linear_graphql(query: &str, variables: Value) -> Option<Value> {
     Some(value)
 }
 
-fn truncate_string(input: &str, limit: usize) -> String {
-    if input.len() <= limit {
-        return input.to_string();
-    }
-    let mut out = input[..limit].to_string();
-    out.push_str("\n…");
-    out
+fn looks_like_issue_identifier(target: &str) -> bool {
+    let Some((team, number)) = target.split_once('-') else {
+        return false;
+    };
+    !team.is_empty()
+        && team.chars().all(|c| c.is_ascii_alphanumeric())
+        && !number.is_empty()
+        && number.chars().all(|c| c.is_ascii_digit())
 }
 
-fn linear_context() -> Option<String> {
-    let projects_query = "query Projects($first: Int!) { projects(first: $first) { nodes { id name description url } } }";
-    let docs_query = "query Docs($first: Int!) { documents(first: $first) { nodes { id title url content project { name url } } } }";
-    let issues_query = "query Issues($first: Int!) { issues(first: $first) { nodes { id title url state { name } project { name url } cycle { name startsAt endsAt } } } }";
-    let cycles_query = "query Cycles($first: Int!) { cycles(first: $first) { nodes { id name number startsAt endsAt isActive isCompleted team { name } } } }";
-
-    let projects = linear_graphql(projects_query, serde_json::json!({ "first": 25 }))?;
-    let docs = linear_graphql(docs_query, serde_json::json!({ "first": 10 }));
-    let issues = linear_graphql(issues_query, serde_json::json!({ "first": 50 }));
-    let cycles = linear_graphql(cycles_query, serde_json::json!({ "first": 20 }));
+fn linear_project_id(linear: &LinearOptions, target: &str) -> Option<String> {
+    let query = "query Projects($first: Int!) { projects(first: $first) { nodes { id name } } }";
+    let value = linear_graphql(linear, query, serde_json::json!({ "first": 250 }))?;
+    let nodes = value.pointer("/data/projects/nodes")?.as_array()?;
+    nodes.iter().find_map(|node| {
+        let id = node.get("id").and_then(|v| v.as_str())?;
+        let name = node.get("name").and_then(|v| v.as_str()).unwrap_or("");
+        if id == target || name.eq_ignore_ascii_case(target) {
+            Some(id.to_string())
+        } else {
+            None
+        }
+    })
+}
 
-    let mut parts = Vec::new();
-    parts.push("Linear projects (raw JSON):".to_string());
-    parts.push(truncate_string(&projects.to_string(), 20000));
-    if let Some(docs_value) = docs {
-        parts.push("Linear documents (raw JSON):".to_string());
-        parts.push(truncate_string(&docs_value.to_string(), 20000));
-    }
-    if let Some(issues_value) = issues {
-        parts.push("Linear issues (raw JSON):".to_string());
-        parts.push(truncate_string(&issues_value.to_string(), 20000));
+fn post_linear_summary(linear: &LinearOptions, target: &str, body: &str) -> io::Result<()> {
+    if looks_like_issue_identifier(target) {
+        let mutation = "mutation Comment($input: CommentCreateInput!) { commentCreate(input: $input) { success } }";
+        let input = serde_json::json!({ "input": { "issueId": target, "body": body } });
+        let value = linear_graphql(linear, mutation, input)
+            .ok_or_else(|| io::Error::other(format!("Linear comment on {target} failed")))?;
+        if value.pointer("/data/commentCreate/success") != Some(&Value::Bool(true)) {
+            return Err(io::Error::other(format!("Linear comment on {target} failed")));
+        }
+        return Ok(());
     }
-    if let Some(cycles_value) = cycles {
-        parts.push("Linear cycles (raw JSON):".to_string());
-        parts.push(truncate_string(&cycles_value.to_string(), 20000));
+    let project_id = linear_project_id(linear, target)
+        .ok_or_else(|| io::Error::other(format!("Linear project not found: {target}")))?;
+    let mutation = "mutation Update($input: ProjectUpdateCreateInput!) { projectUpdateCreate(input: $input) { success } }";
+    let input = serde_json::json!({ "input": { "projectId": project_id, "body": body } });
+    let value = linear_graphql(linear, mutation, input)
+        .ok_or_else(|| io::Error::other(format!("Linear project update for {target} failed")))?;
+    if value.pointer("/data/projectUpdateCreate/success") != Some(&Value::Bool(true)) {
+        return Err(io::Error::other(format!(
+            "Linear project update for {target} failed"
+        )));
     }
-    Some(parts.join("\n\n"))
+    Ok(())
 }
 
-fn read_file_snippet(path: &Path, limit: usize) -> Option<String> {
-    let contents = std::fs::read_to_string(path).ok()?;
-    let mut snippet = contents.trim().to_string();
-    if snippet.len() > limit {
-        snippet.truncate(limit);
-        snippet.push_str("\n…");
-    }
-    if snippet.is_empty() {
-        None
-    } else {
-        Some(snippet)
+fn linear_team_id(linear: &LinearOptions, target: &str) -> Option<String> {
+    let query = "query Teams($first: Int!) { teams(first: $first) { nodes { id key name } } }";
+    let value = linear_graphql(linear, query, serde_json::json!({ "first": 250 }))?;
+    let nodes = value.pointer("/data/teams/nodes")?.as_array()?;
+    nodes.iter().find_map(|node| {
+        let id = node.get("id").and_then(|v| v.as_str())?;
+        let key = node.get("key").and_then(|v| v.as_str()).unwrap_or("");
+        let name = node.get("name").and_then(|v| v.as_str()).unwrap_or("");
+        if id == target || key.eq_ignore_ascii_case(target) || name.eq_ignore_ascii_case(target) {
+            Some(id.to_string())
+        } else {
+            None
+        }
+    })
+}
+
+fn unchecked_tasks(prd: &str) -> Vec<String> {
+    prd.lines()
+        .filter_map(|line| {
+            let line = line.trim_start();
+            line.strip_prefix("- [ ] ")
+                .or_else(|| line.strip_prefix("* [ ] "))
+        })
+        .map(|task| task.trim().to_string())
+        .filter(|task| !task.is_empty())
+        .collect()
+}
+
+fn create_linear_issues_from_tasks(
+    linear: &LinearOptions,
+    prd_path: &Path,
+    team: &str,
+) -> io::Result<()> {
+    let prd = std::fs::read_to_string(prd_path)?;
+    let team_id = linear_team_id(linear, team)
+        .ok_or_else(|| io::Error::other(format!("Linear team not found: {team}")))?;
+    let project_id = match linear.project.as_deref() {
+        Some(project) => Some(
+            linear_project_id(linear, project)
+                .ok_or_else(|| io::Error::other(format!("Linear project not found: {project}")))?,
+        ),
+        None => None,
+    };
+    let mapping_path = prd_path.with_file_name("linear-tasks.json");
+    let mut created: HashMap<String, String> = std::fs::read_to_string(&mapping_path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    let mutation = "mutation Create($input: IssueCreateInput!) { issueCreate(input: $input) { success issue { identifier url } } }";
+    for task in unchecked_tasks(&prd) {
+        if let Some(url) = created.get(&task) {
+            status!("[ralph] exists  {url}  {task}");
+            continue;
+        }
+        let mut input = serde_json::json!({ "teamId": team_id, "title": task });
+        if let Some(project_id) = project_id.as_deref() {
+            input["projectId"] = serde_json::json!(project_id);
+        }
+        let value = linear_graphql(linear, mutation, serde_json::json!({ "input": input }))
+            .filter(|value| value.pointer("/data/issueCreate/success") == Some(&Value::Bool(true)))
+            .ok_or_else(|| io::Error::other(format!("Linear issue creation failed for: {task}")))?;
+        let url = value
+            .pointer("/data/issueCreate/issue/url")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_string();
+        status!("[ralph] created {url}  {task}");
+        created.insert(task, url);
+        let mapping = serde_json::to_string_pretty(&created).map_err(io::Error::other)?;
+        std::fs::write(&mapping_path, mapping)?;
+    }
+    Ok(())
+}
+
+fn truncate_string(input: &str, limit: usize) -> String {
+    if input.len() <= limit {
+        return input.to_string();
+    }
+    let mut out = input[..input.floor_char_boundary(limit)].to_string();
+    out.push_str("\n…");
+    out
+}
+
+fn linear_issue_template(linear: &LinearOptions, issue: &str) -> Option<String> {
+    let query = "query Issue($id: String!) { issue(id: $id) { identifier title description url } }";
+    let value = linear_graphql(linear, query, serde_json::json!({ "id": issue }))?;
+    let node = value.pointer("/data/issue")?;
+    let field = |name: &str| node.get(name).and_then(|v| v.as_str()).unwrap_or("").trim();
+    let title = field("title");
+    if title.is_empty() {
+        return None;
+    }
+    let description = field("description");
+    Some(format!(
+        "# [{}] {title}\n\n{}\n\nIssue: {}\n\nPRD: {{{{PRD}}}}\nProgress log: {{{{PROGRESS}}}}\n",
+        field("identifier"),
+        if description.is_empty() {
+            "(no description)"
+        } else {
+            description
+        },
+        field("url"),
+    ))
+}
+
+/// One bullet per Linear node: title (or cycle dates), state, project and url.
+fn linear_summary_line(node: &Value) -> String {
+    let text = |pointer: &str| {
+        node.pointer(pointer)
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    };
+    let date = |pointer: &str| text(pointer).map(|value| &value[..value.floor_char_boundary(10)]);
+    let mut line = if node.get("isActive").is_some() {
+        let number = node.get("number").and_then(Value::as_u64).unwrap_or_default();
+        let mut line = format!("- {} cycle {number}", text("/team/name").unwrap_or("team"));
+        if let Some(name) = text("/name") {
+            line.push_str(&format!(" \"{name}\""));
+        }
+        if let (Some(start), Some(end)) = (date("/startsAt"), date("/endsAt")) {
+            line.push_str(&format!(" {start} to {end}"));
+        }
+        if node.get("isActive") == Some(&Value::Bool(true)) {
+            line.push_str(" (active)");
+        }
+        line
+    } else {
+        format!("- {}", text("/title").or(text("/name")).unwrap_or("(untitled)"))
+    };
+    if let Some(state) = text("/state/name") {
+        line.push_str(&format!(" [{state}]"));
+    }
+    if let Some(project) = text("/project/name") {
+        line.push_str(&format!(" ({project})"));
+    }
+    if let Some(url) = text("/url") {
+        line.push_str(&format!(" {url}"));
+    }
+    line
+}
+
+fn push_linear_section(
+    parts: &mut Vec<String>,
+    label: &str,
+    value: &Value,
+    nodes: &str,
+    raw: bool,
+    limit: usize,
+) {
+    if raw {
+        parts.push(format!("{label} (raw JSON):"));
+        parts.push(truncate_string(&value.to_string(), limit));
+        return;
+    }
+    let lines: Vec<String> = value
+        .pointer(nodes)
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(linear_summary_line)
+        .collect();
+    parts.push(format!("{label}:"));
+    parts.push(if lines.is_empty() {
+        "(none)".to_string()
+    } else {
+        truncate_string(&lines.join("\n"), limit)
+    });
+}
+
+fn linear_project_context(linear: &LinearOptions, target: &str) -> Option<String> {
+    let project_id = linear_project_id(linear, target)?;
+    let recent = linear.recent_filter();
+    let project_query = format!(
+        "query Project($id: String!) {{ project(id: $id) {{ id name description url documents(first: 10{recent}) {{ nodes {{ id title url content updatedAt }} }} issues(first: 50{recent}) {{ nodes {{ id title url updatedAt state {{ name }} cycle {{ name startsAt endsAt }} }} }} }} }}"
+    );
+    let cycles_query = "query Cycles($first: Int!) { cycles(first: $first) { nodes { id name number startsAt endsAt isActive isCompleted team { name } } } }";
+
+    let project = linear_graphql(linear, &project_query, serde_json::json!({ "id": project_id }))?;
+    let cycles = linear_graphql(linear, cycles_query, serde_json::json!({ "first": 20 }));
+
+    let mut parts = Vec::new();
+    if linear.raw {
+        parts.push(format!("Linear project {target} with documents and issues (raw JSON):"));
+        parts.push(truncate_string(&project.to_string(), 40000));
+    } else {
+        let node = project.pointer("/data/project")?;
+        parts.push(format!("Linear project {target}:"));
+        let mut summary = linear_summary_line(node);
+        if let Some(description) = node.get("description").and_then(Value::as_str)
+            && !description.trim().is_empty()
+        {
+            summary.push_str(&format!("\n{}", truncate_string(description.trim(), 4000)));
+        }
+        parts.push(summary);
+        push_linear_section(
+            &mut parts,
+            "Linear project documents",
+            &project,
+            "/data/project/documents/nodes",
+            false,
+            20000,
+        );
+        push_linear_section(
+            &mut parts,
+            "Linear project issues",
+            &project,
+            "/data/project/issues/nodes",
+            false,
+            20000,
+        );
+    }
+    if let Some(cycles_value) = cycles {
+        push_linear_section(
+            &mut parts,
+            "Linear cycles",
+            &cycles_value,
+            "/data/cycles/nodes",
+            linear.raw,
+            20000,
+        );
+    }
+    Some(parts.join("\n\n"))
+}
+
+fn linear_context(linear: &LinearOptions) -> Option<String> {
+    if let Some(target) = linear.project.as_deref() {
+        return linear_project_context(linear, target);
+    }
+    let projects_query = "query Projects($first: Int!) { projects(first: $first) { nodes { id name description url } } }";
+    let recent = linear.recent_filter();
+    let docs_query = format!(
+        "query Docs($first: Int!) {{ documents(first: $first{recent}) {{ nodes {{ id title url content updatedAt project {{ name url }} }} }} }}"
+    );
+    let issues_query = format!(
+        "query Issues($first: Int!) {{ issues(first: $first{recent}) {{ nodes {{ id title url updatedAt state {{ name }} project {{ name url }} cycle {{ name startsAt endsAt }} }} }} }}"
+    );
+    let cycles_query = "query Cycles($first: Int!) { cycles(first: $first) { nodes { id name number startsAt endsAt isActive isCompleted team { name } } } }";
+
+    let projects = linear_graphql(linear, projects_query, serde_json::json!({ "first": 25 }))?;
+    let docs = linear_graphql(linear, &docs_query, serde_json::json!({ "first": 10 }));
+    let issues = linear_graphql(linear, &issues_query, serde_json::json!({ "first": 50 }));
+    let cycles = linear_graphql(linear, cycles_query, serde_json::json!({ "first": 20 }));
+
+    let mut parts = Vec::new();
+    let sections = [
+        ("Linear projects", Some(projects), "/data/projects/nodes"),
+        ("Linear documents", docs, "/data/documents/nodes"),
+        ("Linear issues", issues, "/data/issues/nodes"),
+        ("Linear cycles", cycles, "/data/cycles/nodes"),
+    ];
+    for (label, value, nodes) in sections {
+        if let Some(value) = value {
+            push_linear_section(&mut parts, label, &value, nodes, linear.raw, 20000);
+        }
+    }
+    Some(parts.join("\n\n"))
+}
+
+fn github_token() -> Option<String> {
+    for name in ["GITHUB_TOKEN", "GH_TOKEN"] {
+        if let Ok(value) = env::var(name) {
+            let trimmed = value.trim().to_string();
+            if !trimmed.is_empty() {
+                return Some(trimmed);
+            }
+        }
+    }
+    None
+}
+
+fn github_repo_slug(origin: &str) -> Option<String> {
+    let trimmed = origin.trim().trim_end_matches('/');
+    let rest = [
+        "git@github.com:",
+        "ssh://git@github.com/",
+        "https://github.com/",
+        "http://github.com/",
+    ]
+    .iter()
+    .find_map(|prefix| trimmed.strip_prefix(prefix))?;
+    let rest = rest.trim_end_matches(".git");
+    let mut parts = rest.split('/');
+    let owner = parts.next().unwrap_or("").trim();
+    let repo = parts.next().unwrap_or("").trim();
+    if owner.is_empty() || repo.is_empty() {
+        return None;
+    }
+    Some(format!("{owner}/{repo}"))
+}
+
+fn github_get(path: &str, token: &str, proxy: Option<&str>) -> Option<Value> {
+    let client = http_client(proxy).build().ok()?;
+    let resp = client
+        .get(format!("https://api.github.com/{path}"))
+        .header("Accept", "application/vnd.github+json")
+        .header("Authorization", format!("Bearer {token}"))
+        .header("User-Agent", "ralph")
+        .send()
+        .ok()?;
+    if !resp.status().is_success() {
+        return None;
+    }
+    resp.json().ok()
+}
+
+fn summarize_github_ci(
+    sha: &str,
+    checks: Option<&Value>,
+    status: Option<&Value>,
+) -> Option<String> {
+    if checks.is_none() && status.is_none() {
+        return None;
+    }
+    let short = &sha[..sha.len().min(12)];
+    let mut lines = vec![format!("commit: {short}")];
+    let runs = checks
+        .and_then(|v| v.get("check_runs"))
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+    if !runs.is_empty() {
+        let mut failing = Vec::new();
+        let mut pending = 0;
+        let mut passing = 0;
+        for run in &runs {
+            let name = run.get("name").and_then(|v| v.as_str()).unwrap_or("?");
+            let state = run.get("status").and_then(|v| v.as_str()).unwrap_or("");
+            let conclusion = run.get("conclusion").and_then(|v| v.as_str()).unwrap_or("");
+            if state != "completed" {
+                pending += 1;
+                continue;
+            }
+            match conclusion {
+                "failure" | "timed_out" | "cancelled" | "action_required" | "startup_failure" => {
+                    let url = run.get("html_url").and_then(|v| v.as_str()).unwrap_or("");
+                    failing.push(
+                        format!("- {name} ({conclusion}) {url}")
+                            .trim_end()
+                            .to_string(),
+                    );
+                }
+                _ => passing += 1,
+            }
+        }
+        lines.push(format!(
+            "check runs: {} total, {} failing, {pending} pending, {passing} passing",
+            runs.len(),
+            failing.len()
+        ));
+        lines.extend(failing);
+    }
+    if let Some(status) = status {
+        let state = status
+            .get("state")
+            .and_then(|v| v.as_str())
+            .unwrap_or("unknown");
+        let statuses = status
+            .get("statuses")
+            .and_then(|v| v.as_array())
+            .cloned()
+            .unwrap_or_default();
+        if !statuses.is_empty() {
+            lines.push(format!("combined status: {state}"));
+            for entry in statuses {
+                let entry_state = entry.get("state").and_then(|v| v.as_str()).unwrap_or("");
+                if entry_state != "failure" && entry_state != "error" {
+                    continue;
+                }
+                let context = entry.get("context").and_then(|v| v.as_str()).unwrap_or("?");
+                let description = entry
+                    .get("description")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("");
+                lines.push(
+                    format!("- {context} ({entry_state}) {description}")
+                        .trim_end()
+                        .to_string(),
+                );
+            }
+        }
+    }
+    if lines.len() == 1 {
+        return None;
+    }
+    Some(lines.join("\n"))
+}
+
+fn github_ci_context(cwd: &Path, proxy: Option<&str>) -> Option<String> {
+    let token = github_token()?;
+    let origin = run_command_output("git", &["remote", "get-url", "origin"], cwd)?;
+    let slug = github_repo_slug(&origin)?;
+    let sha = run_command_output("git", &["rev-parse", "HEAD"], cwd)?;
+    let checks = github_get(
+        &format!("repos/{slug}/commits/{sha}/check-runs?per_page=100"),
+        &token,
+        proxy,
+    );
+    let status = github_get(&format!("repos/{slug}/commits/{sha}/status"), &token, proxy);
+    summarize_github_ci(&sha, checks.as_ref(), status.as_ref())
+}
+
+/// Drops whole-line comments for known file types and collapses blank runs.
+/// Only the in-memory copy is changed.
+fn compress_text(path: &Path, contents: &str) -> String {
+    let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    let comment_prefixes: &[&str] = match ext {
+        "toml" | "py" | "sh" | "yaml" | "yml" | "cfg" => &["#"],
+        "rs" | "js" | "ts" | "c" | "h" | "cpp" | "go" | "java" | "swift" => &["//"],
+        "lean" => &["--"],
+        _ => &[],
+    };
+    let mut lines: Vec<&str> = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim_end();
+        let trimmed = line.trim_start();
+        if comment_prefixes.iter().any(|prefix| trimmed.starts_with(prefix)) {
+            continue;
+        }
+        if ext == "md" && trimmed.starts_with("<!--") && trimmed.ends_with("-->") {
+            continue;
+        }
+        if line.is_empty() && lines.last().is_none_or(|last| last.is_empty()) {
+            continue;
+        }
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+/// Binary files (NUL bytes or invalid UTF-8) are labelled, or skipped with a
+/// note on stderr when `binary_skip` is set.
+fn read_file_snippet(
+    path: &Path,
+    limit: usize,
+    compress: bool,
+    binary_skip: bool,
+) -> Option<String> {
+    let bytes = std::fs::read(path).ok()?;
+    let size = bytes.len();
+    let has_nul = bytes[..size.min(8000)].contains(&0);
+    let contents = match String::from_utf8(bytes) {
+        Ok(text) if !has_nul => text,
+        _ if binary_skip => {
+            eprintln!("[ralph] skipped binary file {}", path.display());
+            return None;
+        }
+        _ => return Some(format!("(binary file, {size} bytes; contents skipped)")),
+    };
+    let contents = if compress {
+        compress_text(path, &contents)
+    } else {
+        contents
+    };
+    let mut snippet = contents.trim().to_string();
+    if snippet.len() > limit {
+        snippet.truncate(snippet.floor_char_boundary(limit));
+        snippet.push_str("\n…");
+    }
+    if snippet.is_empty() {
+        None
+    } else {
+        Some(snippet)
+    }
+}
+
+static CODEX_HOME_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Codex's home directory: `--codex-home`, then `CODEX_HOME`, then `~/.codex`.
+fn codex_home() -> Option<PathBuf> {
+    if let Some(home) = CODEX_HOME_OVERRIDE.get() {
+        return Some(home.clone());
+    }
+    if let Ok(home) = env::var("CODEX_HOME")
+        && !home.trim().is_empty()
+    {
+        return Some(PathBuf::from(home));
     }
+    let home = env::var("HOME").ok()?;
+    Some(Path::new(&home).join(".codex"))
 }
 
 fn ensure_openai_docs_mcp() -> io::Result<()> {
-    let home = match env::var("HOME") {
-        Ok(value) => value,
-        Err(_) => return Ok(()),
+    let Some(home) = codex_home() else {
+        return Ok(());
     };
-    let config_path = Path::new(&home).join(".codex/config.toml");
-    let mut content = std::fs::read_to_string(&config_path).unwrap_or_default();
-    if content.contains("[mcp_servers.openaiDeveloperDocs]") {
+    let config_path = home.join("config.toml");
+    let already_configured = list_mcp_servers().iter().any(|(name, url)| {
+        name.trim_matches('"') == "openaiDeveloperDocs"
+            || url.as_deref() == Some("https://developers.openai.com/mcp")
+    });
+    if already_configured {
         return Ok(());
     }
+    let mut content = std::fs::read_to_string(&config_path).unwrap_or_default();
     if !content.ends_with('\n') {
         content.push('\n');
     }
@@ -499,33 +1933,116 @@ fn ensure_openai_docs_mcp() -> io::Result<()> {
     Ok(())
 }
 
-fn list_mcp_servers() -> Vec<String> {
-    let home = match env::var("HOME") {
-        Ok(value) => value,
-        Err(_) => return Vec::new(),
+fn list_mcp_servers() -> Vec<(String, Option<String>)> {
+    let Some(home) = codex_home() else {
+        return Vec::new();
     };
-    let config_path = Path::new(&home).join(".codex/config.toml");
+    let config_path = home.join("config.toml");
     let content = match std::fs::read_to_string(&config_path) {
         Ok(data) => data,
         Err(_) => return Vec::new(),
     };
-    let mut servers = Vec::new();
+    let mut servers: Vec<(String, Option<String>)> = Vec::new();
+    let mut in_server = false;
     for line in content.lines() {
         let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_server = false;
+        }
         if trimmed.starts_with("[mcp_servers.") && trimmed.ends_with(']') {
             let name = trimmed
                 .trim_start_matches("[mcp_servers.")
                 .trim_end_matches(']');
             if !name.is_empty() {
-                servers.push(name.to_string());
+                servers.push((name.to_string(), None));
+                in_server = true;
             }
+        } else if in_server
+            && let Some((key, value)) = trimmed.split_once('=')
+            && key.trim() == "url"
+            && let Some(server) = servers.last_mut()
+        {
+            server.1 = Some(value.trim().trim_matches('"').to_string());
         }
     }
     servers.sort();
-    servers.dedup();
+    servers.dedup_by(|a, b| a.0 == b.0);
     servers
 }
 
+fn check_mcp_server(url: &str, proxy: Option<&str>) -> Result<u16, String> {
+    let client = http_client(proxy)
+        .timeout(Duration::from_secs(10))
+        .build()
+        .map_err(|err| err.to_string())?;
+    client
+        .get(url)
+        .send()
+        .map(|resp| resp.status().as_u16())
+        .map_err(|err| err.to_string())
+}
+
+fn codex_sessions_dir() -> Option<PathBuf> {
+    Some(codex_home()?.join("sessions"))
+}
+
+fn collect_session_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_session_files(&path, files);
+        } else if path.extension().is_some_and(|ext| ext == "jsonl") {
+            files.push(path);
+        }
+    }
+}
+
+fn read_session_meta(path: &Path) -> Option<(String, PathBuf)> {
+    let file = std::fs::File::open(path).ok()?;
+    let mut first = String::new();
+    io::BufReader::new(file).read_line(&mut first).ok()?;
+    let value: Value = serde_json::from_str(first.trim()).ok()?;
+    let meta = value.get("payload").unwrap_or(&value);
+    let id = meta.get("id").and_then(|v| v.as_str())?.to_string();
+    let cwd = meta.get("cwd").and_then(|v| v.as_str())?;
+    Some((id, PathBuf::from(cwd)))
+}
+
+fn list_codex_sessions(cwd: &Path) -> Vec<(String, SystemTime)> {
+    let Some(dir) = codex_sessions_dir() else {
+        return Vec::new();
+    };
+    let mut files = Vec::new();
+    collect_session_files(&dir, &mut files);
+    let mut sessions = Vec::new();
+    for path in files {
+        let Some((id, session_cwd)) = read_session_meta(&path) else {
+            continue;
+        };
+        if session_cwd != cwd {
+            continue;
+        }
+        let modified = std::fs::metadata(&path)
+            .and_then(|meta| meta.modified())
+            .unwrap_or(UNIX_EPOCH);
+        sessions.push((id, modified));
+    }
+    sessions.sort_by_key(|item| std::cmp::Reverse(item.1));
+    sessions
+}
+
+fn latest_codex_session(cwd: &Path, max_age: Duration) -> Option<String> {
+    let (id, modified) = list_codex_sessions(cwd).into_iter().next()?;
+    let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+    if age > max_age {
+        return None;
+    }
+    Some(id)
+}
+
 fn write_context_snapshot(path: &Path, context: &str) -> io::Result<()> {
     if let Some(parent) = path.parent() {
         create_dir_all(parent)?;
@@ -534,14 +2051,79 @@ fn write_context_snapshot(path: &Path, context: &str) -> io::Result<()> {
     Ok(())
 }
 
+fn context_head_path(context_log: &Path) -> PathBuf {
+    let mut name = context_log.as_os_str().to_owned();
+    name.push(".head");
+    PathBuf::from(name)
+}
+
+fn goal_cache_path(cwd: &Path) -> PathBuf {
+    cwd.join("ralph/goal.json")
+}
+
+fn read_goal_cache(cwd: &Path) -> Option<(String, String)> {
+    let content = std::fs::read_to_string(goal_cache_path(cwd)).ok()?;
+    let value: Value = serde_json::from_str(&content).ok()?;
+    let head = run_command_output("git", &["rev-parse", "HEAD"], cwd);
+    if value.get("head").and_then(|v| v.as_str()) != head.as_deref() {
+        return None;
+    }
+    let field = |name: &str| {
+        value
+            .get(name)
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+            .filter(|s| !s.is_empty())
+    };
+    Some((field("ultimate_goal")?, field("next_action")?))
+}
+
+fn write_goal_cache(cwd: &Path, ultimate: &str, action: &str) -> io::Result<()> {
+    let path = goal_cache_path(cwd);
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let value = serde_json::json!({
+        "head": run_command_output("git", &["rev-parse", "HEAD"], cwd),
+        "ultimate_goal": ultimate,
+        "next_action": action,
+    });
+    std::fs::write(path, format!("{value}\n"))
+}
+
+fn read_cached_context(context_log: &Path, ttl: Duration, head: Option<&str>) -> Option<String> {
+    let modified = std::fs::metadata(context_log).ok()?.modified().ok()?;
+    let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+    if age > ttl {
+        return None;
+    }
+    let cached_head = std::fs::read_to_string(context_head_path(context_log)).ok()?;
+    if Some(cached_head.trim()) != head {
+        return None;
+    }
+    non_empty_string(std::fs::read_to_string(context_log).ok()?)
+}
+
 fn prepare_inference_context(
     repo_name: &str,
     cwd: &Path,
     context_log: Option<&Path>,
+    cache_ttl: Option<Duration>,
+    options: &ContextOptions,
 ) -> io::Result<String> {
-    let context = collect_repo_context(repo_name, cwd);
+    let head = run_command_output("git", &["rev-parse", "HEAD"], cwd);
+    if let (Some(path), Some(ttl)) = (context_log, cache_ttl)
+        && let Some(cached) = read_cached_context(path, ttl, head.as_deref())
+    {
+        eprintln!("[ralph] reusing cached context from {}", path.display());
+        return Ok(cached);
+    }
+    let context = collect_repo_context(repo_name, cwd, options);
     if let Some(path) = context_log {
         let _ = write_context_snapshot(path, &context);
+        if let Some(head) = head.as_deref() {
+            let _ = std::fs::write(context_head_path(path), head);
+        }
     }
     Ok(context)
 }
@@ -554,32 +2136,115 @@ fn write_temp_file(prefix: &str, contents: &str) -> io::Result<PathBuf> {
         .as_millis();
     path.push(format!("{prefix}-{ts}.txt"));
     std::fs::write(&path, contents)?;
+    if let Ok(mut files) = TEMP_FILES.lock() {
+        files.push(path.clone());
+    }
     Ok(path)
 }
 
+/// Temp files created by `write_temp_file`, removed after each iteration and on exit.
+static TEMP_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+fn remove_temp_files() {
+    let Ok(mut files) = TEMP_FILES.lock() else {
+        return;
+    };
+    for path in files.drain(..) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+struct TempFileCleanup;
+
+impl Drop for TempFileCleanup {
+    fn drop(&mut self) {
+        remove_temp_files();
+    }
+}
+
 fn read_with_limit(mut reader: impl Read, limit: usize) -> Vec<u8> {
     let mut buf = Vec::new();
     let mut chunk = [0u8; 8192];
-    while buf.len() < limit {
-        let remaining = limit - buf.len();
-        let read_size = chunk.len().min(remaining);
-        match reader.read(&mut chunk[..read_size]) {
+    loop {
+        match reader.read(&mut chunk) {
             Ok(0) => break,
-            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Ok(n) => {
+                let keep = n.min(limit - buf.len());
+                buf.extend_from_slice(&chunk[..keep]);
+            }
             Err(_) => break,
         }
     }
     buf
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum PromptVia {
+    Stdin,
+    File,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum StopTokenScan {
+    Stdout,
+    Stderr,
+    Both,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ProgressFormat {
+    Text,
+    Jsonl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum SummaryFormat {
+    Json,
+    Markdown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum CodexSandbox {
+    ReadOnly,
+    WorkspaceWrite,
+    DangerFullAccess,
+}
+
+impl CodexSandbox {
+    fn as_str(self) -> &'static str {
+        match self {
+            CodexSandbox::ReadOnly => "read-only",
+            CodexSandbox::WorkspaceWrite => "workspace-write",
+            CodexSandbox::DangerFullAccess => "danger-full-access",
+        }
+    }
+}
+
+enum ProcessInput<'a> {
+    Empty,
+    Text(&'a str),
+    File(&'a Path),
+}
+
+/// Spawns at most two reader threads (stdout/stderr) per call, and ralph runs
+/// one child at a time. Readers keep draining past the capture limit so a
+/// chatty child never blocks on a full pipe. On timeout the readers are left
+/// to finish on their own, since grandchildren may still hold the pipes open.
+/// With `kill_grace`, a timed-out child gets SIGTERM and that long to exit
+/// before it is killed.
 fn run_process_with_timeout(
     mut cmd: Command,
-    input: Option<&str>,
+    input: ProcessInput,
     timeout: Option<Duration>,
     capture_stdout: bool,
     capture_stderr: bool,
+    kill_grace: Option<Duration>,
 ) -> io::Result<Output> {
-    cmd.stdin(Stdio::piped())
+    let stdin = match input {
+        ProcessInput::File(path) => Stdio::from(std::fs::File::open(path)?),
+        _ => Stdio::piped(),
+    };
+    cmd.stdin(stdin)
         .stdout(if capture_stdout {
             Stdio::piped()
         } else {
@@ -592,10 +2257,10 @@ fn run_process_with_timeout(
         });
     let mut child = cmd.spawn()?;
 
-    if let Some(mut stdin) = child.stdin.take() {
-        if let Some(text) = input {
-            stdin.write_all(text.as_bytes())?;
-        }
+    if let Some(mut stdin) = child.stdin.take()
+        && let ProcessInput::Text(text) = input
+    {
+        stdin.write_all(text.as_bytes())?;
     }
 
     let stdout_handle = if capture_stdout {
@@ -617,8 +2282,17 @@ fn run_process_with_timeout(
         match child.wait_timeout(timeout)? {
             Some(status) => status,
             None => {
-                let _ = child.kill();
-                let _ = child.wait();
+                // Graceful SIGTERM is Unix-only; elsewhere the child is killed outright.
+                let exited = kill_grace.filter(|_| cfg!(unix)).is_some_and(|grace| {
+                    let _ = Command::new("kill")
+                        .args(["-TERM", &child.id().to_string()])
+                        .status();
+                    matches!(child.wait_timeout(grace), Ok(Some(_)))
+                });
+                if !exited {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                }
                 return Err(io::Error::new(io::ErrorKind::TimedOut, "Runner timed out"));
             }
         }
@@ -639,96 +2313,323 @@ fn run_process_with_timeout(
     })
 }
 
-fn collect_repo_context(repo_name: &str, cwd: &Path) -> String {
-    let mut lines = Vec::new();
-    lines.push(format!("repo: {repo_name}"));
-    lines.push(format!("path: {}", cwd.display()));
+#[derive(Debug, Clone, Copy)]
+enum ContextLimit {
+    Scale(f64),
+    Absolute(usize),
+}
 
-    let readme_candidates = ["README.md", "Readme.md", "readme.md"];
-    for name in readme_candidates {
-        let path = cwd.join(name);
-        if let Some(snippet) = read_file_snippet(&path, 20000) {
-            lines.push(format!("README ({name}):\n{snippet}"));
-            break;
+/// Parses plain seconds or a duration such as `90s`, `30m`, `8h` or `1h30m`.
+fn parse_duration_secs(value: &str) -> Result<u64, String> {
+    let trimmed = value.trim();
+    if let Ok(secs) = trimmed.parse::<u64>() {
+        return Ok(secs);
+    }
+    let invalid = || format!("expected seconds or a duration like 30m, 8h or 1h30m, got {value}");
+    let mut total: u64 = 0;
+    let mut digits = String::new();
+    for c in trimmed.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
         }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let amount: u64 = digits.parse().map_err(|_| invalid())?;
+        total = amount
+            .checked_mul(unit)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(invalid)?;
+        digits.clear();
     }
+    if !digits.is_empty() || trimmed.is_empty() {
+        return Err(invalid());
+    }
+    Ok(total)
+}
 
-    for name in ["AGENTS.md", "CLAUDE.md"] {
-        let path = cwd.join(name);
-        if let Some(snippet) = read_file_snippet(&path, 12000) {
-            lines.push(format!("{name}:\n{snippet}"));
-        }
+/// Like `parse_duration_secs`, with `none` accepted as 0 (no timeout).
+fn parse_runner_timeout(value: &str) -> Result<u64, String> {
+    if value.trim().eq_ignore_ascii_case("none") {
+        return Ok(0);
     }
+    parse_duration_secs(value)
+}
 
-    for name in ["ralph/PRD.md", "PRD.md", "prd.md"] {
-        let path = cwd.join(name);
-        if let Some(snippet) = read_file_snippet(&path, 12000) {
-            lines.push(format!("PRD ({name}):\n{snippet}"));
-            break;
+fn parse_context_limit(value: &str) -> Result<ContextLimit, String> {
+    let trimmed = value.trim();
+    if let Some(factor) = trimmed.strip_suffix('x') {
+        let factor: f64 = factor
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid multiplier: {value}"))?;
+        if factor <= 0.0 {
+            return Err("multiplier must be positive".to_string());
         }
+        return Ok(ContextLimit::Scale(factor));
     }
+    trimmed
+        .parse::<usize>()
+        .map(ContextLimit::Absolute)
+        .map_err(|_| format!("expected a byte count or a multiplier like 2x, got {value}"))
+}
+
+#[derive(Debug, Clone, Default)]
+struct ContextOptions {
+    file_limit: Option<ContextLimit>,
+    diff_max_files: Option<usize>,
+    compress: bool,
+    binary_skip: bool,
+    command_timeout: Option<Duration>,
+    readme_globs: Vec<String>,
+    priority: Vec<String>,
+    proxy: Option<String>,
+    git_author: Option<String>,
+    env_vars: Vec<String>,
+    redact_prefixes: Vec<String>,
+    linear: LinearOptions,
+}
+
+/// Named sections of `collect_repo_context`, in their default order.
+const CONTEXT_SECTIONS: [&str; 15] = [
+    "readme",
+    "agents",
+    "prd",
+    "progress",
+    "manifests",
+    "linear",
+    "ci",
+    "env",
+    "active-paths",
+    "git",
+    "files",
+    "todos",
+    "status",
+    "diff",
+    "ignored",
+];
+
+impl ContextOptions {
+    /// Runs a context command; a timeout yields a `(skipped: ...)` marker instead of output.
+    fn command(&self, cmd: &str, args: &[&str], cwd: &Path) -> Option<String> {
+        match run_command_output_timeout(cmd, args, cwd, self.command_timeout) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                let secs = self.command_timeout.unwrap_or_default().as_secs();
+                eprintln!("[ralph] `{cmd} {}` timed out after {secs}s; skipping", args.join(" "));
+                Some(format!("(skipped: timed out after {secs}s)"))
+            }
+            Err(_) => None,
+        }
+    }
+
+    fn git_log(&self, count: &str, cwd: &Path) -> Option<String> {
+        let author = self.git_author.as_ref().map(|author| format!("--author={author}"));
+        let mut args = vec!["log", count, "--oneline"];
+        args.extend(author.as_deref());
+        self.command("git", &args, cwd)
+    }
+
+    fn snippet(&self, path: &Path, default_limit: usize) -> Option<String> {
+        read_file_snippet(path, self.limit(default_limit), self.compress, self.binary_skip)
+    }
+
+    fn limit(&self, default: usize) -> usize {
+        match self.file_limit {
+            Some(ContextLimit::Scale(factor)) => {
+                ((default as f64) * factor).round().max(1.0) as usize
+            }
+            Some(ContextLimit::Absolute(limit)) => limit,
+            None => default,
+        }
+    }
+}
+
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| glob_match(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && glob_match(rest, &name[1..]),
+        Some((byte, rest)) => name.first() == Some(byte) && glob_match(rest, &name[1..]),
+    }
+}
+
+/// Expands `dir/pattern` globs relative to `cwd`; wildcards apply to the file name only.
+fn expand_globs(cwd: &Path, globs: &[String]) -> Vec<String> {
+    let mut matches = Vec::new();
+    for glob in globs {
+        let (dir, pattern) = match glob.rsplit_once('/') {
+            Some((dir, pattern)) => (dir, pattern),
+            None => ("", glob.as_str()),
+        };
+        let Ok(entries) = std::fs::read_dir(cwd.join(dir)) else {
+            continue;
+        };
+        let mut found: Vec<String> = entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().is_file())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|name| glob_match(pattern.as_bytes(), name.as_bytes()))
+            .map(|name| {
+                if dir.is_empty() {
+                    name
+                } else {
+                    format!("{dir}/{name}")
+                }
+            })
+            .collect();
+        found.sort();
+        for name in found {
+            if !matches.contains(&name) {
+                matches.push(name);
+            }
+        }
+    }
+    matches
+}
+
+fn collect_repo_context(repo_name: &str, cwd: &Path, options: &ContextOptions) -> String {
+    let header = vec![
+        format!("repo: {repo_name}"),
+        format!("path: {}", cwd.display()),
+    ];
+    let mut sections: Vec<(&str, Vec<String>)> = Vec::new();
+    let mut lines = Vec::new();
+
+    if options.readme_globs.is_empty() {
+        let readme_candidates = [
+            "README.md",
+            "Readme.md",
+            "readme.md",
+            "README.markdown",
+            "README.rst",
+            "README.txt",
+            "README.adoc",
+            "README.org",
+            "README",
+        ];
+        for name in readme_candidates {
+            let path = cwd.join(name);
+            if let Some(snippet) = options.snippet(&path, 20000) {
+                lines.push(format!("README ({name}):\n{snippet}"));
+                break;
+            }
+        }
+    } else {
+        for name in expand_globs(cwd, &options.readme_globs) {
+            let path = cwd.join(&name);
+            if let Some(snippet) = options.snippet(&path, 20000) {
+                lines.push(format!("README ({name}):\n{snippet}"));
+            }
+        }
+    }
+    sections.push(("readme", std::mem::take(&mut lines)));
+
+    for name in ["AGENTS.md", "CLAUDE.md"] {
+        let path = cwd.join(name);
+        if let Some(snippet) = options.snippet(&path, 12000) {
+            lines.push(format!("{name}:\n{snippet}"));
+        }
+    }
+    sections.push(("agents", std::mem::take(&mut lines)));
+
+    for name in ["ralph/PRD.md", "PRD.md", "prd.md"] {
+        let path = cwd.join(name);
+        if let Some(snippet) = options.snippet(&path, 12000) {
+            lines.push(format!("PRD ({name}):\n{snippet}"));
+            break;
+        }
+    }
+    sections.push(("prd", std::mem::take(&mut lines)));
 
     for name in ["ralph/progress.txt", "progress.txt"] {
         let path = cwd.join(name);
-        if let Some(snippet) = read_file_snippet(&path, 8000) {
+        if let Some(snippet) = options.snippet(&path, 8000) {
             lines.push(format!("Ralph progress log ({name}):\n{snippet}"));
             break;
         }
     }
+    sections.push(("progress", std::mem::take(&mut lines)));
 
     for name in ["Cargo.toml", "lakefile.lean", "package.json", "pyproject.toml"] {
         let path = cwd.join(name);
-        if let Some(snippet) = read_file_snippet(&path, 8000) {
+        if let Some(snippet) = options.snippet(&path, 8000) {
             lines.push(format!("{name}:\n{snippet}"));
         }
     }
+    sections.push(("manifests", std::mem::take(&mut lines)));
 
-    if let Some(linear) = linear_context() {
+    if let Some(linear) = linear_context(&options.linear) {
         lines.push(format!("Linear context (use for ultimate goal if relevant):\n{linear}"));
     } else {
         lines.push("Linear context: unavailable".to_string());
     }
+    sections.push(("linear", std::mem::take(&mut lines)));
+
+    append_context(
+        &mut lines,
+        "GitHub CI status for HEAD (if failing, fixing CI is the next action)",
+        github_ci_context(cwd, options.proxy.as_deref()),
+        options.limit(4000),
+    );
+    sections.push(("ci", std::mem::take(&mut lines)));
+
+    append_context(
+        &mut lines,
+        "Environment variables",
+        env_context(&options.env_vars, &options.redact_prefixes),
+        options.limit(4000),
+    );
+    sections.push(("env", std::mem::take(&mut lines)));
 
-    let diff_stat_raw = run_command_output("git", &["diff", "--stat"], cwd).unwrap_or_default();
+    let diff_stat_raw = options.command("git", &["diff", "--stat"], cwd).unwrap_or_default();
     let (diff_stat_filtered, diff_stat_ignored) = filter_diffstat_for_context(&diff_stat_raw);
     if let Some(summary) = summarize_active_paths(&diff_stat_filtered) {
         lines.push(format!(
             "Active paths from diffstat (hint for next action): {summary}"
         ));
     }
+    sections.push(("active-paths", std::mem::take(&mut lines)));
 
-    let status_raw = run_command_output("git", &["status", "--short"], cwd).unwrap_or_default();
+    let status_raw = options.command("git", &["status", "--short"], cwd).unwrap_or_default();
     let (status_filtered, status_ignored) = filter_git_status_for_context(&status_raw);
 
     append_context(
         &mut lines,
         "git origin",
-        run_command_output("git", &["remote", "get-url", "origin"], cwd),
-        2000,
+        options.command("git", &["remote", "get-url", "origin"], cwd),
+        options.limit(2000),
     );
     append_context(
         &mut lines,
         "git last commit",
-        run_command_output("git", &["log", "-1", "--oneline"], cwd),
-        2000,
+        options.git_log("-1", cwd),
+        options.limit(2000),
     );
     append_context(
         &mut lines,
         "git recent commits",
-        run_command_output("git", &["log", "-10", "--oneline"], cwd),
-        8000,
+        options.git_log("-10", cwd),
+        options.limit(8000),
     );
+    sections.push(("git", std::mem::take(&mut lines)));
     append_context(
         &mut lines,
         "tracked files",
-        run_command_output("git", &["ls-files"], cwd),
-        20000,
+        options.command("git", &["ls-files"], cwd),
+        options.limit(20000),
     );
+    sections.push(("files", std::mem::take(&mut lines)));
 
     append_context(
         &mut lines,
         "worktree TODO/FIXME/XXX (use for next action)",
-        run_command_output(
+        options.command(
             "rg",
             &[
                 "-n",
@@ -740,35 +2641,52 @@ fn collect_repo_context(repo_name: &str, cwd: &Path) -> String {
             ],
             cwd,
         ),
-        12000,
+        options.limit(12000),
     );
+    sections.push(("todos", std::mem::take(&mut lines)));
 
     append_context(
         &mut lines,
         "worktree git status (use for next action)",
         non_empty_string(status_filtered),
-        4000,
+        options.limit(4000),
     );
+    sections.push(("status", std::mem::take(&mut lines)));
+    let diff_stat_filtered = match options.diff_max_files {
+        Some(max_files) => limit_diffstat_files(&diff_stat_filtered, max_files),
+        None => diff_stat_filtered,
+    };
     append_context(
         &mut lines,
         "worktree git diff --stat (use for next action)",
         non_empty_string(diff_stat_filtered),
-        4000,
+        options.limit(4000),
     );
+    sections.push(("diff", std::mem::take(&mut lines)));
     append_context(
         &mut lines,
         "worktree ignored dataset/cache artifacts (low priority unless referenced elsewhere)",
         non_empty_string(status_ignored),
-        2000,
+        options.limit(2000),
     );
     append_context(
         &mut lines,
         "diffstat ignored dataset/cache artifacts (low priority unless referenced elsewhere)",
         non_empty_string(diff_stat_ignored),
-        2000,
+        options.limit(2000),
     );
+    sections.push(("ignored", lines));
 
-    lines.join("\n\n")
+    let mut ordered = header;
+    for name in &options.priority {
+        if let Some(index) = sections.iter().position(|(section, _)| section == name) {
+            ordered.extend(sections.remove(index).1);
+        }
+    }
+    for (_, section) in sections {
+        ordered.extend(section);
+    }
+    ordered.join("\n\n")
 }
 
 fn extract_json_block(text: &str) -> Option<String> {
@@ -789,8 +2707,12 @@ fn extract_json_block(text: &str) -> Option<String> {
             return Some(body);
         }
     }
-    let start = trimmed.find('{')?;
-    let end = trimmed.rfind('}')?;
+    let array_first = trimmed
+        .find('[')
+        .is_some_and(|bracket| trimmed.find('{').is_none_or(|brace| bracket < brace));
+    let (open, close) = if array_first { ('[', ']') } else { ('{', '}') };
+    let start = trimmed.find(open)?;
+    let end = trimmed.rfind(close)?;
     if end <= start {
         return None;
     }
@@ -799,7 +2721,7 @@ fn extract_json_block(text: &str) -> Option<String> {
 
 fn prompt_for_feedback() -> io::Result<String> {
     loop {
-        println!("[ralph] Provide corrections or desired direction for the goal/next action.");
+        status!("[ralph] Provide corrections or desired direction for the goal/next action.");
         print!("[ralph] feedback> ");
         io::stdout().flush()?;
         let mut input = String::new();
@@ -808,13 +2730,24 @@ fn prompt_for_feedback() -> io::Result<String> {
         if !trimmed.is_empty() {
             return Ok(trimmed);
         }
-        println!("[ralph] Feedback cannot be empty.");
+        status!("[ralph] Feedback cannot be empty.");
+    }
+}
+
+/// Accepts a single `{ultimate_goal, next_action}` object or an array of them.
+fn parse_goal_payload(output: &str) -> Vec<(String, String)> {
+    let Some(value) = extract_json_block(output)
+        .and_then(|candidate| serde_json::from_str::<Value>(&candidate).ok())
+    else {
+        return Vec::new();
+    };
+    match value {
+        Value::Array(items) => items.iter().filter_map(goal_from_value).collect(),
+        value => goal_from_value(&value).into_iter().collect(),
     }
 }
 
-fn parse_goal_payload(output: &str) -> Option<(String, String)> {
-    let candidate = extract_json_block(output)?;
-    let value: Value = serde_json::from_str(&candidate).ok()?;
+fn goal_from_value(value: &Value) -> Option<(String, String)> {
     let ultimate = value
         .get("ultimate_goal")
         .or_else(|| value.get("goal"))
@@ -829,18 +2762,66 @@ fn parse_goal_payload(output: &str) -> Option<(String, String)> {
     Some((ultimate, next_action))
 }
 
+fn choose_goal_candidate(
+    mut candidates: Vec<(String, String)>,
+) -> io::Result<Option<(String, String)>> {
+    if candidates.len() <= 1 || !io::stdin().is_terminal() {
+        if candidates.len() > 1 {
+            eprintln!(
+                "[ralph] {} goal candidates; stdin is not a terminal, using the first.",
+                candidates.len()
+            );
+        }
+        return Ok(candidates.into_iter().next());
+    }
+    for (index, (goal, action)) in candidates.iter().enumerate() {
+        eprintln!("[ralph] {}) goal: {goal}", index + 1);
+        eprintln!("[ralph]    next action: {action}");
+    }
+    loop {
+        eprint!(
+            "[ralph] Choose a candidate [1-{}] (default 1): ",
+            candidates.len()
+        );
+        io::stderr().flush()?;
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input)? == 0 {
+            return Ok(Some(candidates.swap_remove(0)));
+        }
+        let choice = input.trim();
+        if choice.is_empty() {
+            return Ok(Some(candidates.swap_remove(0)));
+        }
+        match choice.parse::<usize>() {
+            Ok(index) if (1..=candidates.len()).contains(&index) => {
+                return Ok(Some(candidates.swap_remove(index - 1)));
+            }
+            _ => eprintln!("[ralph] Enter a number between 1 and {}.", candidates.len()),
+        }
+    }
+}
+
 fn build_inference_prompt(
     context: &str,
     feedback: Option<&str>,
     previous: Option<(String, String)>,
+    schema: Option<&Value>,
+    candidates: u32,
+    include_diff: bool,
 ) -> String {
+    let diff_rule = if include_diff {
+        "also weigh uncommitted diffs for the goal, since work in progress may define it."
+    } else {
+        "ignore uncommitted diffs for the goal."
+    };
     let mut prompt = format!(
         "You are a repo analyst. Infer the ultimate project goal and the next concrete action.\n\
 Ultimate goal is a stable, long-horizon objective; next action is immediate and concrete.\n\
-Prioritize README/AGENTS/CLAUDE/PRD/Linear for the ultimate goal; ignore uncommitted diffs for the goal.\n\
+Prioritize README/AGENTS/CLAUDE/PRD/Linear for the ultimate goal; {diff_rule}\n\
 For next action, use worktree TODOs, git status/diff, and progress log; keep it small and concrete.\n\
 Do not pick cleanup of ignored dataset/cache artifacts unless they are mentioned in TODO/progress/Linear or they block tests.\n\
 If ignored artifacts look like real work and are referenced elsewhere, call that out explicitly.\n\
+If GitHub CI status shows failing checks, the next action should fix them before anything else.\n\
 If active paths are listed, bias the next action toward that subproject when consistent with README/PRD.\n\
 If Linear context is present, only use entries that match the repo name or purpose.\n\
 Return ONLY JSON: {{\"ultimate_goal\":\"...\",\"next_action\":\"...\"}}.\n\
@@ -857,255 +2838,2199 @@ Context:\n{context}"
     if let Some(note) = feedback {
         prompt.push_str(&format!("\n\nUser feedback:\n{note}\n"));
     }
-    prompt
-}
-
-fn infer_goal_with_codex(
-    context: &str,
-    model: &str,
-    effort: &str,
-    yolo: bool,
-    specialization: Option<&str>,
-    feedback: Option<&str>,
-    previous: Option<(String, String)>,
-    runner_timeout: Option<Duration>,
-    codex_json: bool,
-) -> io::Result<Option<(String, String)>> {
-    let prompt = build_inference_prompt(context, feedback, previous);
-    let output = run_codex(
-        &prompt,
-        model,
-        effort,
-        &[],
-        false,
-        yolo,
-        false,
-        None,
-        specialization,
-        codex_json,
-        runner_timeout,
-    )?;
-    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-    Ok(parse_goal_payload(&stdout))
-}
-
-fn infer_goal_with_sdk(
-    context: &str,
-    model: &str,
-    effort: &str,
-    specialization: Option<&str>,
-    feedback: Option<&str>,
-    previous: Option<(String, String)>,
-    sdk_max_turns: u32,
-    runner_timeout: Option<Duration>,
-) -> io::Result<Option<(String, String)>> {
-    let prompt = build_inference_prompt(context, feedback, previous);
-    let output = run_sdk(
-        &prompt,
-        model,
-        effort,
-        specialization,
-        sdk_max_turns,
-        runner_timeout,
-    )?;
-    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-    Ok(parse_goal_payload(&stdout))
-}
-
-fn ensure_file(path: &Path, content: &str) -> io::Result<()> {
-    if let Some(parent) = path.parent() {
-        create_dir_all(parent)?;
+    if candidates > 1 {
+        prompt.push_str(&format!(
+            "\n\nInstead of one object, return ONLY a JSON array of {candidates} distinct objects with the same keys, best first.\n"
+        ));
     }
-    if !path.exists() {
-        std::fs::write(path, content)?;
+    if let Some(schema) = schema {
+        prompt.push_str(&format!(
+            "\n\nThe JSON must also validate against this JSON schema:\n{schema}\n"
+        ));
     }
-    Ok(())
+    prompt
 }
 
-fn append_log(
-    log_path: &Path,
-    iteration: u32,
-    stdout: &[u8],
-    stderr: &[u8],
-    status: &ExitStatus,
-) -> io::Result<()> {
-    if let Some(parent) = log_path.parent() {
-        create_dir_all(parent)?;
-    }
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(log_path)?;
-
-    let ts = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs();
-    writeln!(file, "[iteration {iteration}] {ts}")?;
-    if !stdout.is_empty() {
-        writeln!(file, "\n[stdout]")?;
-        file.write_all(stdout)?;
-    }
-    if !stderr.is_empty() {
-        writeln!(file, "\n[stderr]")?;
-        file.write_all(stderr)?;
-    }
-    writeln!(file, "\n[exit-code] {:?}", status.code())?;
-    writeln!(file, "\n{}", "-".repeat(80))?;
-    Ok(())
+fn check_goal_schema(output: &str, schema: Option<&Value>) -> io::Result<()> {
+    let Some(schema) = schema else {
+        return Ok(());
+    };
+    let value = extract_json_block(output)
+        .and_then(|candidate| serde_json::from_str::<Value>(&candidate).ok())
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Inference output is not JSON")
+        })?;
+    let result = match &value {
+        Value::Array(items) => items.iter().enumerate().try_for_each(|(index, item)| {
+            validate_json_schema(item, schema, &format!("$[{index}]"))
+        }),
+        value => validate_json_schema(value, schema, "$"),
+    };
+    result.map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Inference output does not match schema: {err}"),
+        )
+    })
 }
 
-fn run_codex(
-    prompt: &str,
-    model: &str,
-    effort: &str,
-    runner_args: &[String],
-    full_auto: bool,
-    yolo: bool,
-    resume_last: bool,
-    resume_id: Option<&str>,
-    specialization: Option<&str>,
-    codex_json: bool,
-    runner_timeout: Option<Duration>,
-) -> io::Result<Output> {
-    let mut cmd = Command::new("codex");
-    if !model.is_empty() {
-        cmd.args(["--model", model]);
-    }
-    if !effort.is_empty() {
-        cmd.args(["-c", &format!("model_reasoning_effort={}", effort)]);
-    }
-    if let Some(spec) = specialization {
-        if !spec.trim().is_empty() {
-            cmd.args(["-c", &format!("specialization={}", spec)]);
+/// Checks the common JSON Schema keywords: `type`, `enum`, `const`, `required`,
+/// `properties`, `additionalProperties`, `items`, string/array lengths and
+/// numeric bounds. Other keywords are ignored.
+fn validate_json_schema(value: &Value, schema: &Value, path: &str) -> Result<(), String> {
+    if let Some(expected) = schema.get("type") {
+        let types: Vec<&str> = match expected {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(|name| name.as_str()).collect(),
+            _ => Vec::new(),
+        };
+        let matches = |name: &str| match name {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            _ => true,
+        };
+        if !types.is_empty() && !types.iter().any(|name| matches(name)) {
+            return Err(format!("{path}: expected type {}", types.join("|")));
         }
     }
-    if yolo {
-        cmd.arg("--dangerously-bypass-approvals-and-sandbox");
-    } else if full_auto {
-        cmd.arg("--full-auto");
+    if let Some(options) = schema.get("enum").and_then(|v| v.as_array())
+        && !options.contains(value)
+    {
+        return Err(format!("{path}: value not in enum"));
     }
-    cmd.arg("exec");
-    if codex_json {
-        cmd.arg("--json");
+    if let Some(expected) = schema.get("const")
+        && expected != value
+    {
+        return Err(format!("{path}: expected {expected}"));
     }
-    let output_path = write_temp_file("ralph-last-message", "")?;
-    cmd.args(["--output-last-message", output_path.to_string_lossy().as_ref()]);
-    if resume_last || resume_id.is_some() {
-        cmd.arg("resume");
-        if let Some(id) = resume_id {
-            cmd.arg(id);
+    let bound = |key: &str| schema.get(key).and_then(|v| v.as_f64());
+    let length = match value {
+        Value::String(text) => Some(text.chars().count() as f64),
+        Value::Array(items) => Some(items.len() as f64),
+        _ => None,
+    };
+    if let Some(len) = length {
+        let (min_key, max_key) = if value.is_string() {
+            ("minLength", "maxLength")
         } else {
-            cmd.arg("--last");
+            ("minItems", "maxItems")
+        };
+        if bound(min_key).is_some_and(|min| len < min) {
+            return Err(format!("{path}: violates {min_key}"));
+        }
+        if bound(max_key).is_some_and(|max| len > max) {
+            return Err(format!("{path}: violates {max_key}"));
         }
     }
-    if !runner_args.is_empty() {
-        cmd.args(runner_args);
+    if let Some(number) = value.as_f64() {
+        if bound("minimum").is_some_and(|min| number < min) {
+            return Err(format!("{path}: {number} is below minimum"));
+        }
+        if bound("maximum").is_some_and(|max| number > max) {
+            return Err(format!("{path}: {number} is above maximum"));
+        }
     }
-    cmd.arg("-");
-    let mut output = run_process_with_timeout(
-        cmd,
-        Some(prompt),
-        runner_timeout,
-        !codex_json,
-        true,
-    )?;
-    if let Ok(message) = std::fs::read_to_string(&output_path) {
-        if !message.trim().is_empty() {
-            output.stdout = message.into_bytes();
+    if let (Some(items), Some(item_schema)) = (value.as_array(), schema.get("items")) {
+        for (index, item) in items.iter().enumerate() {
+            validate_json_schema(item, item_schema, &format!("{path}[{index}]"))?;
         }
     }
-    Ok(output)
+    if let Some(object) = value.as_object() {
+        let required = schema.get("required").and_then(|v| v.as_array());
+        for key in required
+            .into_iter()
+            .flatten()
+            .filter_map(|key| key.as_str())
+        {
+            if !object.contains_key(key) {
+                return Err(format!("{path}: missing required key {key}"));
+            }
+        }
+        let properties = schema.get("properties").and_then(|v| v.as_object());
+        for (key, field) in object {
+            let field_path = format!("{path}.{key}");
+            match (
+                properties.and_then(|props| props.get(key)),
+                schema.get("additionalProperties"),
+            ) {
+                (Some(field_schema), _) => validate_json_schema(field, field_schema, &field_path)?,
+                (None, Some(Value::Bool(false))) => {
+                    return Err(format!("{path}: unexpected key {key}"));
+                }
+                (None, Some(extra)) if extra.is_object() => {
+                    validate_json_schema(field, extra, &field_path)?
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
 }
 
-fn has_arg(args: &[String], needle: &str) -> bool {
-    args.iter().any(|arg| arg == needle)
+fn with_progress<T>(label: &str, work: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let interactive = io::stderr().is_terminal();
+    if !interactive {
+        eprintln!("[ralph] {label}...");
+    }
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    let result = thread::scope(|scope| {
+        if interactive {
+            scope.spawn(move || {
+                while let Err(mpsc::RecvTimeoutError::Timeout) =
+                    done_rx.recv_timeout(Duration::from_secs(1))
+                {
+                    eprint!("\r[ralph] {label}... {}s", start.elapsed().as_secs());
+                }
+            });
+        }
+        let result = work();
+        drop(done_tx);
+        result
+    });
+    if interactive {
+        eprintln!("\r[ralph] {label} done in {}s", start.elapsed().as_secs());
+    }
+    result
 }
 
-fn run_generic(
-    runner: &str,
-    model: &str,
-    prompt_flag: &str,
-    prompt: &str,
-    runner_args: &[String],
-    yolo: bool,
-    runner_timeout: Option<Duration>,
-) -> io::Result<Output> {
-    let mut cmd = Command::new(runner);
-    if !model.is_empty() {
-        cmd.args(["--model", model]);
-    }
-    let mut args = runner_args.to_vec();
-    if yolo && runner == "claude" && !has_arg(&args, "--dangerously-skip-permissions") {
-        args.push("--dangerously-skip-permissions".to_string());
+fn build_plan_prompt(context: &str, task: &str, goal: &str) -> String {
+    let mut prompt = String::from(
+        "You are planning work on this repository before any changes are made.\n\
+Write a concise, numbered, step-by-step plan in Markdown for the task below.\n\
+Call out risks and how each step will be verified. Do not modify any files.\n",
+    );
+    if !goal.is_empty() {
+        prompt.push_str(&format!("\nGoal: {goal}\n"));
     }
-    if !args.is_empty() {
-        cmd.args(&args);
+    if !task.trim().is_empty() {
+        prompt.push_str(&format!("\nTask prompt:\n{task}\n"));
     }
-    cmd.arg(prompt_flag).arg(prompt);
-    run_process_with_timeout(cmd, None, runner_timeout, true, true)
+    prompt.push_str(&format!("\nContext:\n{context}"));
+    prompt
 }
 
-fn run_sdk(
-    prompt: &str,
-    model: &str,
-    effort: &str,
-    specialization: Option<&str>,
-    max_turns: u32,
-    runner_timeout: Option<Duration>,
-) -> io::Result<Output> {
-    let prompt_path = write_temp_file("ralph-prompt", prompt)?;
-    let mut cmd = Command::new("uv");
-    cmd.args([
-        "run",
-        "python",
-        "scripts/ralph_agent.py",
-        "--prompt-file",
-        prompt_path.to_string_lossy().as_ref(),
-        "--model",
-        model,
-        "--max-turns",
-        &max_turns.to_string(),
-        "--reasoning-effort",
-        effort,
-    ]);
-    if let Some(spec) = specialization {
-        if !spec.trim().is_empty() {
-            cmd.args(["--specialization", spec]);
+/// A goal-inference request; `feedback` and `previous` refine an earlier proposal.
+struct GoalRequest<'a> {
+    context: &'a str,
+    feedback: Option<&'a str>,
+    previous: Option<(String, String)>,
+    candidates: u32,
+    schema: Option<&'a Value>,
+    include_diff: bool,
+}
+
+/// Proposes an ultimate goal and next action with the SDK runner, or codex otherwise.
+fn infer_goal(
+    request: GoalRequest<'_>,
+    options: &RunnerOptions,
+) -> io::Result<Option<(String, String)>> {
+    let prompt = build_inference_prompt(
+        request.context,
+        request.feedback,
+        request.previous,
+        request.schema,
+        request.candidates,
+        request.include_diff,
+    );
+    let call = Invocation {
+        prompt: &prompt,
+        model: &options.model,
+        effort: &options.effort,
+        runner_args: &[],
+        resume_last: false,
+        resume_id: None,
+    };
+    let output = with_progress("inferring goal", || {
+        if options.runner == "sdk" {
+            run_sdk(&call, options)
+        } else {
+            let codex = RunnerOptions {
+                full_auto: false,
+                sandbox_mode: None,
+                prompt_via: PromptVia::Stdin,
+                tools: Vec::new(),
+                ..options.clone()
+            };
+            run_codex(&call, &codex, None)
         }
-    }
-    run_process_with_timeout(cmd, None, runner_timeout, true, true)
+    })?;
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    check_goal_schema(&stdout, request.schema)?;
+    choose_goal_candidate(parse_goal_payload(&stdout))
 }
-fn ensure_runner(runner: &str) -> io::Result<()> {
-    let found = which::which(runner).map_err(|_| {
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct ScheduleEntry {
+    model: Option<String>,
+    reasoning_effort: Option<String>,
+    runner_arg: Option<Vec<String>>,
+}
+
+fn load_schedule(path: &Path) -> io::Result<HashMap<u32, ScheduleEntry>> {
+    let invalid = |message: String| {
         io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("Runner not found: {runner}"),
+            io::ErrorKind::InvalidInput,
+            format!("Invalid schedule file {}: {message}", path.display()),
         )
-    })?;
-    let _ = found;
-    Ok(())
+    };
+    let content = std::fs::read_to_string(path)?;
+    let entries: HashMap<String, ScheduleEntry> =
+        serde_json::from_str(&content).map_err(|err| invalid(err.to_string()))?;
+    entries
+        .into_iter()
+        .map(|(key, entry)| match key.trim().parse::<u32>() {
+            Ok(iteration) if iteration > 0 => Ok((iteration, entry)),
+            _ => Err(invalid(format!("`{key}` is not an iteration number"))),
+        })
+        .collect()
 }
 
-fn main() -> io::Result<()> {
-    let args = Args::parse();
-    let cwd = env::current_dir()?;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RunSummary {
+    repo: String,
+    runner: String,
+    model: String,
+    started_at: u64,
+    duration_secs: u64,
+    iterations: u32,
+    stop_reason: Option<String>,
+    exit_code: i32,
+    start_head: Option<String>,
+    #[serde(default)]
+    tool_calls: ToolCallCounts,
+    #[serde(default)]
+    baseline: Option<String>,
+    #[serde(default)]
+    stop_evaluation: StopEvaluation,
+    #[serde(default)]
+    seed: u64,
+    #[serde(default)]
+    tokens: u64,
+}
 
-    let default_prd = cwd.join("ralph/PRD.md");
-    let default_progress = cwd.join("ralph/progress.txt");
-    let default_template = cwd.join("ralph/prompt-template.md");
-    let default_log = cwd.join("ralph/overnight.log");
+fn run_summary_path(cwd: &Path) -> PathBuf {
+    cwd.join("ralph/last-run.json")
+}
 
-    let runner = args.runner;
-    let model = args.model;
-    let reasoning_effort = args.reasoning_effort;
-    let iterations = args.iterations;
-    let sleep_secs = args.sleep;
-    let max_seconds = args.max_seconds;
-    let specialization = args.specialization.as_deref();
-    let codex_json = args.codex_json;
-    let runner_timeout = if args.runner_timeout > 0 {
-        Some(Duration::from_secs(args.runner_timeout))
+fn read_run_summary(cwd: &Path) -> Option<RunSummary> {
+    let content = std::fs::read_to_string(run_summary_path(cwd)).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+fn write_run_summary(cwd: &Path, summary: &RunSummary) -> io::Result<()> {
+    let path = run_summary_path(cwd);
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let content = serde_json::to_string_pretty(summary).map_err(io::Error::other)?;
+    std::fs::write(path, content)
+}
+
+fn write_run_summary_markdown(cwd: &Path, summary: &RunSummary) -> io::Result<PathBuf> {
+    let path = cwd.join("ralph/run-summary.md");
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut lines = vec![
+        format!("# Ralph run summary ({})", summary.repo),
+        String::new(),
+        "## Outcome".to_string(),
+        String::new(),
+        format!(
+            "- Stop reason: {}",
+            summary.stop_reason.as_deref().unwrap_or("unknown")
+        ),
+        format!("- Exit code: {}", summary.exit_code),
+        format!("- Runner: {} ({})", summary.runner, summary.model),
+    ];
+    if !summary.stop_evaluation.checks.is_empty() {
+        lines.push(format!(
+            "- Stop checks (iteration {}):",
+            summary.stop_evaluation.iteration
+        ));
+        for line in summary.stop_evaluation.lines() {
+            lines.push(format!("  - {line}"));
+        }
+    }
+    lines.extend([
+        String::new(),
+        "## Iterations".to_string(),
+        String::new(),
+        format!("- Completed: {}", summary.iterations),
+        String::new(),
+        "## Timings".to_string(),
+        String::new(),
+        format!("- Started: {}", format_utc(summary.started_at)),
+        format!("- Duration: {}s", summary.duration_secs),
+    ]);
+    if summary.iterations > 0 {
+        lines.push(format!(
+            "- Average per iteration: {}s",
+            summary.duration_secs / u64::from(summary.iterations)
+        ));
+    }
+    if summary.tool_calls.total() > 0 {
+        let calls = &summary.tool_calls;
+        lines.extend([
+            String::new(),
+            "## Tool calls".to_string(),
+            String::new(),
+            format!("- Shell commands: {}", calls.shell_commands),
+            format!("- File edits: {}", calls.file_edits),
+            format!("- MCP calls: {}", calls.mcp_calls),
+            format!("- Web searches: {}", calls.web_searches),
+        ]);
+    }
+    lines.push(String::new());
+    std::fs::write(&path, lines.join("\n"))?;
+    Ok(path)
+}
+
+fn seen_label(seen: bool) -> &'static str {
+    if seen { "seen" } else { "not seen" }
+}
+
+fn render_run_review(summary: &RunSummary, cwd: &Path) -> String {
+    let mut lines = vec![
+        format!("## Ralph run review ({})", summary.repo),
+        String::new(),
+        format!(
+            "- Outcome: {}",
+            summary.stop_reason.as_deref().unwrap_or("unknown")
+        ),
+        format!("- Iterations: {}", summary.iterations),
+        format!("- Duration: {}s", summary.duration_secs),
+        format!("- Runner: {} ({})", summary.runner, summary.model),
+    ];
+    if !summary.stop_evaluation.checks.is_empty() {
+        lines.push(format!(
+            "- Stop checks: {}",
+            summary.stop_evaluation.lines().join("; ")
+        ));
+    }
+    if let Some(baseline) = summary.baseline.as_deref() {
+        lines.push(format!("- Baseline: {baseline} (`git diff {baseline}` for all changes)"));
+    }
+    if summary.tool_calls.total() > 0 {
+        let calls = &summary.tool_calls;
+        lines.push(format!(
+            "- Tool calls: {} shell, {} file edits, {} MCP, {} web searches",
+            calls.shell_commands, calls.file_edits, calls.mcp_calls, calls.web_searches
+        ));
+    }
+    if let Some(head) = summary.start_head.as_deref() {
+        let range = format!("{head}..HEAD");
+        if let Some(commits) = run_command_output("git", &["log", "--oneline", &range], cwd) {
+            lines.push(String::new());
+            lines.push("### Commits".to_string());
+            lines.push(truncate_string(&commits, 4000));
+        }
+        if let Some(diffstat) = run_command_output("git", &["diff", "--stat", head], cwd) {
+            lines.push(String::new());
+            lines.push("### Changes".to_string());
+            lines.push(format!("```\n{}\n```", truncate_string(&diffstat, 4000)));
+        }
+    }
+    lines.join("\n")
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn format_utc(timestamp: u64) -> String {
+    let days = (timestamp / 86_400) as i64;
+    let secs = timestamp % 86_400;
+    // Civil-from-days conversion (proleptic Gregorian calendar).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02} UTC",
+        secs / 3600,
+        secs % 3600 / 60
+    )
+}
+
+/// One JSONL progress entry: `{"ts", "iteration", "status", "note"}`.
+fn progress_entry(iteration: u32, status: &str, note: &str) -> String {
+    let entry = serde_json::json!({
+        "ts": unix_timestamp(),
+        "iteration": iteration,
+        "status": status,
+        "note": note,
+    });
+    format!("{entry}\n")
+}
+
+fn append_progress_entry(path: &Path, iteration: u32, status: &str, note: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(progress_entry(iteration, status, note).as_bytes())
+}
+
+fn append_run_summary(path: &Path, summary: &RunSummary, format: ProgressFormat) -> io::Result<()> {
+    if format == ProgressFormat::Jsonl {
+        let note = format!(
+            "run finished after {} iterations in {}s (exit code {})",
+            summary.iterations, summary.duration_secs, summary.exit_code
+        );
+        let status = summary.stop_reason.as_deref().unwrap_or("unknown");
+        return append_progress_entry(path, summary.iterations, status, &note);
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "\n## Ralph run {}", format_utc(summary.started_at))?;
+    writeln!(
+        file,
+        "- Stop reason: {}",
+        summary.stop_reason.as_deref().unwrap_or("unknown")
+    )?;
+    writeln!(file, "- Iterations: {}", summary.iterations)?;
+    writeln!(file, "- Duration: {}s", summary.duration_secs)?;
+    writeln!(file, "- Runner: {} ({})", summary.runner, summary.model)?;
+    writeln!(file, "- Exit code: {}", summary.exit_code)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn connect_event_socket(path: &Path) -> Option<UnixStream> {
+    match UnixStream::connect(path) {
+        Ok(stream) => Some(stream),
+        Err(err) => {
+            eprintln!(
+                "[ralph] event socket {} unavailable: {err}; continuing without events.",
+                path.display()
+            );
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn connect_event_socket(path: &Path) -> Option<io::Sink> {
+    eprintln!(
+        "[ralph] event socket {} needs Unix domain sockets; continuing without events.",
+        path.display()
+    );
+    None
+}
+
+fn emit_event(sink: &mut Option<impl Write>, event: &str, fields: Value) {
+    let Some(stream) = sink.as_mut() else {
+        return;
+    };
+    let mut payload = serde_json::json!({ "event": event, "ts": unix_timestamp() });
+    if let (Some(target), Value::Object(extra)) = (payload.as_object_mut(), fields) {
+        target.extend(extra);
+    }
+    let line = format!("{payload}\n");
+    if stream.write_all(line.as_bytes()).is_err() {
+        eprintln!("[ralph] event socket disconnected; continuing without events.");
+        *sink = None;
+    }
+}
+
+fn run_repo_hook(
+    cwd: &Path,
+    name: &str,
+    env: &[(&str, String)],
+    log_path: Option<&Path>,
+    redact: Option<&[String]>,
+) {
+    let hook = cwd.join(".ralph/hooks").join(name);
+    #[cfg(unix)]
+    let executable = std::fs::metadata(&hook)
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false);
+    #[cfg(not(unix))]
+    let executable = hook.is_file();
+    if !executable {
+        return;
+    }
+    status!("[ralph] hook: {name}");
+    let output = match Command::new(&hook)
+        .current_dir(cwd)
+        .envs(env.iter().map(|(key, value)| (*key, value.as_str())))
+        .output()
+    {
+        Ok(output) => output,
+        Err(err) => {
+            eprintln!("[ralph] hook {name} failed to start: {err}");
+            return;
+        }
+    };
+    let _ = io::stdout().write_all(&output.stdout);
+    let _ = io::stderr().write_all(&output.stderr);
+    if !output.status.success() {
+        eprintln!("[ralph] hook {name} exited with {:?}", output.status.code());
+    }
+    if let Some(path) = log_path
+        && let Err(err) = append_command_log(path, "hook", &hook.to_string_lossy(), &output, redact)
+    {
+        eprintln!("[ralph] could not log hook output: {err}");
+    }
+}
+
+fn run_post_run_command(
+    command: &str,
+    cwd: &Path,
+    stop_reason: &str,
+    exit_code: i32,
+    log_path: Option<&Path>,
+    redact: Option<&[String]>,
+) {
+    status!("[ralph] post-run: {command}");
+    let result = Command::new("sh")
+        .args(["-c", command])
+        .current_dir(cwd)
+        .env("RALPH_STOP_REASON", stop_reason)
+        .env("RALPH_EXIT_CODE", exit_code.to_string())
+        .output();
+    let output = match result {
+        Ok(output) => output,
+        Err(err) => {
+            eprintln!("[ralph] post-run command failed to start: {err}");
+            return;
+        }
+    };
+    let _ = io::stdout().write_all(&output.stdout);
+    let _ = io::stderr().write_all(&output.stderr);
+    if !output.status.success() {
+        eprintln!(
+            "[ralph] post-run command exited with {:?}",
+            output.status.code()
+        );
+    }
+    if let Some(path) = log_path
+        && let Err(err) = append_command_log(path, "post-run", command, &output, redact)
+    {
+        eprintln!("[ralph] could not log post-run output: {err}");
+    }
+}
+
+fn append_command_log(
+    log_path: &Path,
+    label: &str,
+    command: &str,
+    output: &Output,
+    redact: Option<&[String]>,
+) -> io::Result<()> {
+    if let Some(parent) = log_path.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)?;
+    let redacted = |bytes: &[u8]| match redact {
+        Some(prefixes) => redact_secrets(bytes, prefixes),
+        None => bytes.to_vec(),
+    };
+    let command = redacted(command.as_bytes());
+    writeln!(file, "[{label}] {} {}", unix_timestamp(), String::from_utf8_lossy(&command))?;
+    if !output.stdout.is_empty() {
+        writeln!(file, "\n[stdout]")?;
+        file.write_all(&redacted(&output.stdout))?;
+    }
+    if !output.stderr.is_empty() {
+        writeln!(file, "\n[stderr]")?;
+        file.write_all(&redacted(&output.stderr))?;
+    }
+    writeln!(file, "\n[exit-code] {:?}", output.status.code())?;
+    writeln!(file, "\n{}", "-".repeat(80))?;
+    Ok(())
+}
+
+fn ensure_file(path: &Path, content: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    if !path.exists() {
+        std::fs::write(path, content)?;
+    }
+    Ok(())
+}
+
+fn is_secret_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-'
+}
+
+/// Whether all of `text` matches glob `pattern`: `*` matches any run of bytes, `?` any one.
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, t));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+fn redact_secrets(input: &[u8], patterns: &[String]) -> Vec<u8> {
+    let defaults = [
+        "sk-", "lin_api_", "ghp_", "gho_", "ghs_", "github_pat_", "glpat-", "xoxb-", "xoxp-",
+        "AKIA",
+    ];
+    let (globs, extra_prefixes): (Vec<&String>, Vec<&String>) = patterns
+        .iter()
+        .filter(|p| !p.is_empty())
+        .partition(|p| p.contains(['*', '?']));
+    let prefixes: Vec<&[u8]> = defaults
+        .iter()
+        .map(|p| p.as_bytes())
+        .chain(extra_prefixes.iter().map(|p| p.as_bytes()))
+        .collect();
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        if input[i].is_ascii_whitespace() {
+            out.push(input[i]);
+            i += 1;
+            continue;
+        }
+        let len = input[i..].iter().take_while(|b| !b.is_ascii_whitespace()).count();
+        let word = &input[i..i + len];
+        if globs.iter().any(|glob| glob_matches(glob.as_bytes(), word)) {
+            out.extend_from_slice(b"[REDACTED]");
+        } else {
+            redact_prefixed_tokens(word, &prefixes, &mut out);
+        }
+        i += len;
+    }
+    out
+}
+
+/// Masks each token that starts with one of `prefixes` and continues for 8+ token characters.
+fn redact_prefixed_tokens(word: &[u8], prefixes: &[&[u8]], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < word.len() {
+        let at_boundary = i == 0 || !is_secret_char(word[i - 1]);
+        let matched = at_boundary
+            .then(|| prefixes.iter().find(|p| word[i..].starts_with(p)))
+            .flatten();
+        if let Some(prefix) = matched {
+            let tail = word[i + prefix.len()..]
+                .iter()
+                .take_while(|b| is_secret_char(**b))
+                .count();
+            if tail >= 8 {
+                out.extend_from_slice(b"[REDACTED]");
+                i += prefix.len() + tail;
+                continue;
+            }
+        }
+        out.push(word[i]);
+        i += 1;
+    }
+}
+
+fn append_log(
+    log_path: &Path,
+    iteration: u32,
+    stdout: &[u8],
+    stderr: &[u8],
+    status: &ExitStatus,
+    slow_secs: Option<u64>,
+    redact: Option<&[String]>,
+) -> io::Result<()> {
+    if let Some(parent) = log_path.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)?;
+
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    writeln!(file, "[iteration {iteration}] {ts}")?;
+    let (stdout, stderr) = match redact {
+        Some(prefixes) => (
+            redact_secrets(stdout, prefixes),
+            redact_secrets(stderr, prefixes),
+        ),
+        None => (stdout.to_vec(), stderr.to_vec()),
+    };
+    if !stdout.is_empty() {
+        writeln!(file, "\n[stdout]")?;
+        file.write_all(&stdout)?;
+    }
+    if !stderr.is_empty() {
+        writeln!(file, "\n[stderr]")?;
+        file.write_all(&stderr)?;
+    }
+    writeln!(file, "\n[exit-code] {:?}", status.code())?;
+    if let Some(secs) = slow_secs {
+        writeln!(file, "[SLOW] {secs}s")?;
+    }
+    writeln!(file, "\n{}", "-".repeat(80))?;
+    Ok(())
+}
+
+struct LogRecord {
+    iteration: u32,
+    ts: u64,
+    exit_code: Option<i32>,
+    stdout: String,
+}
+
+/// Parses the `append_log` text format, or JSONL entries with `iteration`,
+/// `ts` and `exit_code` fields.
+fn parse_run_log(content: &str) -> Vec<LogRecord> {
+    let mut records: Vec<LogRecord> = Vec::new();
+    let mut in_stdout = false;
+    for line in content.lines() {
+        if line.starts_with('{') {
+            let Ok(entry) = serde_json::from_str::<Value>(line) else {
+                continue;
+            };
+            let Some(iteration) = entry.get("iteration").and_then(Value::as_u64) else {
+                continue;
+            };
+            records.push(LogRecord {
+                iteration: iteration as u32,
+                ts: entry.get("ts").and_then(Value::as_u64).unwrap_or_default(),
+                exit_code: entry
+                    .get("exit_code")
+                    .and_then(Value::as_i64)
+                    .map(|code| code as i32),
+                stdout: entry
+                    .get("note")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+            });
+        } else if let Some(rest) = line.strip_prefix("[iteration ") {
+            let (iteration, ts) = rest.split_once("] ").unwrap_or((rest, ""));
+            records.push(LogRecord {
+                iteration: iteration.trim_end_matches(']').parse().unwrap_or_default(),
+                ts: ts.trim().parse().unwrap_or_default(),
+                exit_code: None,
+                stdout: String::new(),
+            });
+            in_stdout = false;
+        } else if let Some(record) = records.last_mut() {
+            if line == "[stdout]" {
+                in_stdout = true;
+            } else if line == "[stderr]" {
+                in_stdout = false;
+            } else if let Some(code) = line.strip_prefix("[exit-code] ") {
+                in_stdout = false;
+                record.exit_code = code
+                    .trim()
+                    .strip_prefix("Some(")
+                    .and_then(|code| code.strip_suffix(')'))
+                    .and_then(|code| code.parse().ok());
+            } else if in_stdout {
+                record.stdout.push_str(line);
+                record.stdout.push('\n');
+            }
+        }
+    }
+    records
+}
+
+fn replay_log(path: &Path, stop_tokens: &[String]) -> io::Result<()> {
+    let content = std::fs::read_to_string(path)?;
+    let records = parse_run_log(&content);
+    status!("[ralph] replaying {}", path.display());
+    if records.is_empty() {
+        status!("[ralph] no iterations found.");
+        return Ok(());
+    }
+    let first_ts = records[0].ts;
+    let mut failures = 0;
+    let mut stop_fired = None;
+    for record in &records {
+        let exit = match record.exit_code {
+            Some(code) => code.to_string(),
+            None => "unknown".to_string(),
+        };
+        if record.exit_code.is_some_and(|code| code != 0) {
+            failures += 1;
+        }
+        let token = find_stop_token(record.stdout.as_bytes(), stop_tokens);
+        if token.is_some() && stop_fired.is_none() {
+            stop_fired = Some(record.iteration);
+        }
+        status!(
+            "[ralph] iteration {}: exit {exit} at +{}s{}",
+            record.iteration,
+            record.ts.saturating_sub(first_ts),
+            token
+                .map(|token| format!(" (stop token {token})"))
+                .unwrap_or_default()
+        );
+    }
+    let last_ts = records.last().map(|record| record.ts).unwrap_or(first_ts);
+    status!(
+        "[ralph] {} iterations, {failures} failed, {}s between first and last iteration",
+        records.len(),
+        last_ts.saturating_sub(first_ts)
+    );
+    match stop_fired {
+        Some(iteration) => status!("[ralph] stop token fired at iteration {iteration}"),
+        None => status!("[ralph] stop token never fired"),
+    }
+    Ok(())
+}
+
+/// Drops the supervisor and budget flags so the child can be given what is left.
+fn child_args_without(
+    args: impl Iterator<Item = std::ffi::OsString>,
+    flags: &[&str],
+    value_flags: &[&str],
+) -> Vec<std::ffi::OsString> {
+    let mut kept = Vec::new();
+    let mut skip_value = false;
+    for arg in args {
+        if std::mem::take(&mut skip_value) {
+            continue;
+        }
+        let text = arg.to_string_lossy();
+        let name = text.split('=').next().unwrap_or_default();
+        if flags.contains(&name) || (value_flags.contains(&name) && text.contains('=')) {
+            continue;
+        }
+        if value_flags.contains(&name) {
+            skip_value = true;
+            continue;
+        }
+        kept.push(arg);
+    }
+    kept
+}
+
+fn supervise(args: &Args) -> io::Result<i32> {
+    let exe = env::current_exe()?;
+    supervise_with(args, env::args_os().skip(1), |child_args| {
+        Command::new(&exe).args(child_args).status()
+    })
+}
+
+/// Runs children via `spawn` until one exits without crashing. Each restart continues the
+/// iteration numbering (task files, schedule, model rotation) and the token budget from
+/// where the crashed child's `--supervise-state` left off.
+fn supervise_with(
+    args: &Args,
+    argv: impl Iterator<Item = std::ffi::OsString>,
+    mut spawn: impl FnMut(&[std::ffi::OsString]) -> io::Result<ExitStatus>,
+) -> io::Result<i32> {
+    let child_args = child_args_without(
+        argv,
+        &["--supervise", "--once"],
+        &[
+            "--supervise-max-restarts",
+            "--supervise-state",
+            "--start-iteration",
+            "--tokens-used",
+            "--iterations",
+            "--max-iterations",
+            "--max-seconds",
+            "--max-duration",
+        ],
+    );
+    let iterations = if args.once { 1 } else { args.iterations };
+    let state_path = env::temp_dir().join(format!("ralph-supervise-{}.txt", std::process::id()));
+    let start = Instant::now();
+    let mut completed = args.start_iteration - 1;
+    let mut tokens = args.tokens_used;
+    let mut restarts = 0u32;
+    loop {
+        let seconds_left = args.max_seconds.saturating_sub(start.elapsed().as_secs());
+        if completed >= iterations || (args.max_seconds > 0 && seconds_left == 0) {
+            status!("[ralph] supervisor: budget used up after {completed} iterations");
+            return Ok(0);
+        }
+        let _ = std::fs::remove_file(&state_path);
+        let mut child = child_args.clone();
+        child.extend(
+            [
+                "--iterations".to_string(),
+                iterations.to_string(),
+                "--start-iteration".to_string(),
+                (completed + 1).to_string(),
+                "--tokens-used".to_string(),
+                tokens.to_string(),
+            ]
+            .map(Into::into),
+        );
+        child.extend(["--supervise-state".into(), state_path.clone().into_os_string()]);
+        if args.max_seconds > 0 {
+            child.extend(["--max-seconds".into(), seconds_left.to_string().into()]);
+        }
+        let status = spawn(&child)?;
+        let state = std::fs::read_to_string(&state_path).unwrap_or_default();
+        let _ = std::fs::remove_file(&state_path);
+        let mut fields = state.split_whitespace().map(str::parse::<u64>);
+        if let (Some(Ok(done)), Some(Ok(used))) = (fields.next(), fields.next()) {
+            completed = u32::try_from(done).unwrap_or(u32::MAX);
+            tokens = used;
+        }
+        // Rust panics exit with 101; signals mean the child died mid-run.
+        if exit_signal(&status).is_none() && status.code() != Some(101) {
+            return Ok(status.code().unwrap_or(1));
+        }
+        restarts += 1;
+        let reason = match exit_signal(&status) {
+            Some(signal) => format!("signal {signal}"),
+            None => "panic".to_string(),
+        };
+        if restarts > args.supervise_max_restarts {
+            eprintln!(
+                "[ralph] supervisor: child crashed ({reason}); giving up after {} restarts",
+                args.supervise_max_restarts
+            );
+            return Ok(status.code().unwrap_or(1));
+        }
+        eprintln!(
+            "[ralph] supervisor: child crashed ({reason}); restarting ({restarts}/{}) at iteration {}",
+            args.supervise_max_restarts,
+            completed + 1
+        );
+    }
+}
+
+/// Runner settings fixed for the whole run, built once from `Args`.
+#[derive(Debug, Clone)]
+struct RunnerOptions {
+    runner: String,
+    model: String,
+    effort: String,
+    yolo: bool,
+    full_auto: bool,
+    sandbox_mode: Option<CodexSandbox>,
+    specialization: Option<String>,
+    codex_json: bool,
+    timeout: Option<Duration>,
+    kill_grace: Option<Duration>,
+    env: Vec<(String, String)>,
+    profile: Option<String>,
+    tools: Vec<String>,
+    prompt_via: PromptVia,
+    prompt_flags: Vec<String>,
+    sdk_max_turns: u32,
+    sdk_python: Vec<String>,
+}
+
+/// The prompt and per-call settings (schedule overrides, resume) for one runner call.
+struct Invocation<'a> {
+    prompt: &'a str,
+    model: &'a str,
+    effort: &'a str,
+    runner_args: &'a [String],
+    resume_last: bool,
+    resume_id: Option<&'a str>,
+}
+
+/// Runs `call` with `options.runner`; only codex reports `events`.
+fn run_agent(
+    call: &Invocation<'_>,
+    options: &RunnerOptions,
+    events: Option<&mut CodexEvents>,
+) -> io::Result<Output> {
+    match options.runner.as_str() {
+        "codex" => run_codex(call, options, events),
+        "sdk" => run_sdk(call, options),
+        "claude" => run_claude(call, options),
+        runner => run_generic(runner, call, options),
+    }
+}
+
+fn run_codex(
+    call: &Invocation<'_>,
+    options: &RunnerOptions,
+    events: Option<&mut CodexEvents>,
+) -> io::Result<Output> {
+    let mut cmd = Command::new("codex");
+    if let Some(home) = CODEX_HOME_OVERRIDE.get() {
+        cmd.env("CODEX_HOME", home);
+    }
+    if let Some(profile) = options.profile.as_deref() {
+        cmd.args(["--profile", profile]);
+    }
+    if !call.model.is_empty() {
+        cmd.args(["--model", call.model]);
+    }
+    if !call.effort.is_empty() {
+        cmd.args(["-c", &format!("model_reasoning_effort={}", call.effort)]);
+    }
+    if let Some(spec) = options.specialization.as_deref()
+        && !spec.trim().is_empty()
+    {
+        cmd.args(["-c", &format!("specialization={}", spec)]);
+    }
+    for tool in &options.tools {
+        let (name, enabled) = tool.split_once('=').unwrap_or((tool, "true"));
+        cmd.args(["-c", &format!("tools.{name}={enabled}")]);
+    }
+    if let Some(mode) = options.sandbox_mode {
+        cmd.args(["--sandbox", mode.as_str()]);
+    } else if options.yolo {
+        cmd.arg("--dangerously-bypass-approvals-and-sandbox");
+    } else if options.full_auto {
+        cmd.arg("--full-auto");
+    }
+    cmd.arg("exec");
+    if options.codex_json {
+        cmd.arg("--json");
+    }
+    let output_path = write_temp_file("ralph-last-message", "")?;
+    cmd.args(["--output-last-message", output_path.to_string_lossy().as_ref()]);
+    if call.resume_last || call.resume_id.is_some() {
+        cmd.arg("resume");
+        if let Some(id) = call.resume_id {
+            cmd.arg(id);
+        } else {
+            cmd.arg("--last");
+        }
+    }
+    if !call.runner_args.is_empty() {
+        cmd.args(call.runner_args);
+    }
+    cmd.arg("-");
+    let prompt_path = match options.prompt_via {
+        PromptVia::File => Some(write_temp_file("ralph-codex-prompt", call.prompt)?),
+        PromptVia::Stdin => None,
+    };
+    let input = match prompt_path.as_deref() {
+        Some(path) => {
+            status!("[ralph] codex prompt file: {}", path.display());
+            ProcessInput::File(path)
+        }
+        None => ProcessInput::Text(call.prompt),
+    };
+    cmd.envs(options.env.iter().map(|(key, value)| (key, value)));
+    let mut output =
+        run_process_with_timeout(cmd, input, options.timeout, true, true, options.kill_grace)?;
+    if options.codex_json {
+        if let Some(events) = events {
+            events.tool_calls.add(&parse_codex_events(&output.stdout));
+            events.tokens += parse_codex_token_usage(&output.stdout);
+            if let Some((field, value)) = events.stop_field.as_ref() {
+                events.stop_seen |= json_event_matches(&output.stdout, field, value);
+            }
+        }
+        output.stdout.clear();
+    }
+    if let Ok(message) = std::fs::read_to_string(&output_path)
+        && !message.trim().is_empty()
+    {
+        output.stdout = message.into_bytes();
+    }
+    Ok(output)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ToolCallCounts {
+    shell_commands: u64,
+    file_edits: u64,
+    mcp_calls: u64,
+    web_searches: u64,
+}
+
+impl ToolCallCounts {
+    fn add(&mut self, other: &ToolCallCounts) {
+        self.shell_commands += other.shell_commands;
+        self.file_edits += other.file_edits;
+        self.mcp_calls += other.mcp_calls;
+        self.web_searches += other.web_searches;
+    }
+
+    fn total(&self) -> u64 {
+        self.shell_commands + self.file_edits + self.mcp_calls + self.web_searches
+    }
+}
+
+/// State gathered from `codex exec --json` event streams across iterations.
+#[derive(Debug, Default)]
+struct CodexEvents {
+    tool_calls: ToolCallCounts,
+    tokens: u64,
+    stop_field: Option<(String, String)>,
+    stop_seen: bool,
+}
+
+fn parse_json_stop_field(value: &str) -> Result<(String, String), String> {
+    let (field, expected) = value
+        .split_once('=')
+        .ok_or_else(|| "expected FIELD=VALUE".to_string())?;
+    let field = field.trim().trim_start_matches("$.");
+    if field.is_empty() {
+        return Err("field must not be empty".to_string());
+    }
+    let pointer = if field.starts_with('/') {
+        field.to_string()
+    } else {
+        format!("/{}", field.replace('.', "/"))
+    };
+    Ok((pointer, expected.to_string()))
+}
+
+/// True when any JSON line has the field (a JSON pointer) equal to `expected`;
+/// non-string values are compared after parsing `expected` as JSON.
+fn json_event_matches(stdout: &[u8], pointer: &str, expected: &str) -> bool {
+    String::from_utf8_lossy(stdout).lines().any(|line| {
+        serde_json::from_str::<Value>(line)
+            .ok()
+            .and_then(|event| event.pointer(pointer).cloned())
+            .is_some_and(|found| match found {
+                Value::String(text) => text == expected,
+                other => serde_json::from_str::<Value>(expected).is_ok_and(|value| value == other),
+            })
+    })
+}
+
+/// Sums input and output tokens from `turn.completed` usage events, or from
+/// the older `token_count` messages.
+fn parse_codex_token_usage(stdout: &[u8]) -> u64 {
+    let field = |value: &Value, pointer: &str| value.pointer(pointer).and_then(Value::as_u64);
+    String::from_utf8_lossy(stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .map(|event| {
+            if let Some(usage) = event.get("usage") {
+                field(usage, "/input_tokens").unwrap_or_default()
+                    + field(usage, "/output_tokens").unwrap_or_default()
+            } else if event.pointer("/msg/type").and_then(Value::as_str) == Some("token_count") {
+                field(&event, "/msg/info/last_token_usage/total_tokens").unwrap_or_default()
+            } else {
+                0
+            }
+        })
+        .sum()
+}
+
+/// Counts tool calls in `codex exec --json` output, accepting both the
+/// `item.completed` event stream and the older `msg` event stream.
+fn parse_codex_events(stdout: &[u8]) -> ToolCallCounts {
+    let mut counts = ToolCallCounts::default();
+    for line in String::from_utf8_lossy(stdout).lines() {
+        let Ok(event) = serde_json::from_str::<Value>(line) else {
+            continue;
+        };
+        let kind = if event.get("type").and_then(|v| v.as_str()) == Some("item.completed") {
+            event.pointer("/item/type").and_then(|v| v.as_str())
+        } else {
+            event.pointer("/msg/type").and_then(|v| v.as_str())
+        };
+        match kind.unwrap_or_default() {
+            "command_execution" | "exec_command_begin" => counts.shell_commands += 1,
+            "file_change" | "patch_apply_begin" => counts.file_edits += 1,
+            "mcp_tool_call" | "mcp_tool_call_begin" => counts.mcp_calls += 1,
+            "web_search" | "web_search_begin" => counts.web_searches += 1,
+            _ => {}
+        }
+    }
+    counts
+}
+
+/// Matches the token's UTF-8 bytes against the raw output, so invalid UTF-8
+/// elsewhere in the stream never hides or fabricates a match.
+fn contains_stop_token(output: &[u8], token: &str) -> bool {
+    let needle = token.as_bytes();
+    if needle.is_empty() {
+        return false;
+    }
+    output.windows(needle.len()).any(|window| window == needle)
+}
+
+fn find_stop_token<'a>(output: &[u8], tokens: &'a [String]) -> Option<&'a str> {
+    tokens
+        .iter()
+        .find(|token| contains_stop_token(output, token))
+        .map(String::as_str)
+}
+
+fn load_stop_tokens(primary: &str, list: Option<&Path>) -> io::Result<Vec<String>> {
+    let mut tokens = vec![primary.to_string()];
+    if let Some(path) = list {
+        for line in std::fs::read_to_string(path)?.lines() {
+            let token = line.trim();
+            if !token.is_empty() && !token.starts_with('#') && !tokens.iter().any(|t| t == token) {
+                tokens.push(token.to_string());
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Returns `(byte offset of '@', path)` for each word-initial `@path` reference.
+fn file_refs(prompt: &str) -> Vec<(usize, &str)> {
+    prompt
+        .match_indices('@')
+        .filter(|(index, _)| {
+            prompt[..*index]
+                .chars()
+                .next_back()
+                .is_none_or(|prev| prev.is_whitespace() || matches!(prev, '(' | '[' | '`'))
+        })
+        .filter_map(|(index, _)| {
+            let rest = &prompt[index + 1..];
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let path = rest[..end].trim_end_matches(['.', ',', ';', ':', ')', ']', '`', '"', '\'']);
+            (!path.is_empty()).then_some((index, path))
+        })
+        .collect()
+}
+
+/// Keeps at most `max` distinct `@path` references, preferring `priority` paths and then
+/// order of appearance. Dropped references keep their path text but lose the `@`.
+fn cap_file_refs(prompt: &str, max: usize, priority: &[&Path]) -> (String, Vec<String>) {
+    let refs = file_refs(prompt);
+    let mut distinct: Vec<&str> = Vec::new();
+    for (_, path) in &refs {
+        if !distinct.contains(path) {
+            distinct.push(path);
+        }
+    }
+    if distinct.len() <= max {
+        return (prompt.to_string(), Vec::new());
+    }
+    let rank = |path: &str| {
+        priority
+            .iter()
+            .position(|preferred| preferred.display().to_string() == path)
+            .unwrap_or(priority.len())
+    };
+    let mut ordered = distinct.clone();
+    ordered.sort_by_key(|path| rank(path));
+    let dropped: Vec<&str> = ordered.split_off(max);
+    let mut capped = String::with_capacity(prompt.len());
+    let mut last = 0;
+    for (index, path) in &refs {
+        if dropped.contains(path) {
+            capped.push_str(&prompt[last..*index]);
+            last = index + 1;
+        }
+    }
+    capped.push_str(&prompt[last..]);
+    (capped, dropped.into_iter().map(str::to_string).collect())
+}
+
+fn lint_prompt(prompt: &str, stop_token: &str, source: &str) {
+    if !stop_token.is_empty() && !prompt.contains(stop_token) {
+        eprintln!(
+            "[ralph] warning: {source} never mentions the stop token {stop_token}; the loop can only end by exhaustion."
+        );
+    }
+}
+
+fn strip_stop_token(output: &[u8], token: &str, trailing: bool) -> Vec<u8> {
+    let needle = token.as_bytes();
+    if needle.is_empty() {
+        return output.to_vec();
+    }
+    let mut stripped = Vec::with_capacity(output.len());
+    let mut rest = output;
+    while let Some(pos) = rest.windows(needle.len()).position(|window| window == needle) {
+        stripped.extend_from_slice(&rest[..pos]);
+        if trailing {
+            if !stripped.is_empty() && !stripped.ends_with(b"\n") {
+                stripped.push(b'\n');
+            }
+            return stripped;
+        }
+        rest = &rest[pos + needle.len()..];
+    }
+    stripped.extend_from_slice(rest);
+    stripped
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum StopCondition {
+    Token,
+    Iterations(u32),
+    Seconds(u64),
+    Idle(u32),
+    File(PathBuf),
+    And(Vec<StopCondition>),
+    Or(Vec<StopCondition>),
+}
+
+/// Every stop condition checked during the final iteration, with its value.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct StopEvaluation {
+    iteration: u32,
+    checks: Vec<StopCheck>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StopCheck {
+    condition: String,
+    value: String,
+    triggered: bool,
+}
+
+impl StopEvaluation {
+    fn start(&mut self, iteration: u32) {
+        self.iteration = iteration;
+        self.checks.clear();
+    }
+
+    /// Records a check and returns whether it triggered.
+    fn record(&mut self, condition: &str, value: String, triggered: bool) -> bool {
+        self.checks.push(StopCheck {
+            condition: condition.to_string(),
+            value,
+            triggered,
+        });
+        triggered
+    }
+
+    fn lines(&self) -> Vec<String> {
+        self.checks
+            .iter()
+            .map(|check| {
+                let mark = if check.triggered { " (triggered)" } else { "" };
+                format!("{}: {}{mark}", check.condition, check.value)
+            })
+            .collect()
+    }
+}
+
+struct StopState<'a> {
+    token_seen: bool,
+    iterations: u32,
+    elapsed: Duration,
+    idle_streak: u32,
+    cwd: &'a Path,
+}
+
+impl StopCondition {
+    fn evaluate(&self, state: &StopState) -> bool {
+        match self {
+            StopCondition::Token => state.token_seen,
+            StopCondition::Iterations(n) => state.iterations >= *n,
+            StopCondition::Seconds(n) => state.elapsed.as_secs() >= *n,
+            StopCondition::Idle(n) => state.idle_streak >= *n,
+            StopCondition::File(path) => state.cwd.join(path).exists(),
+            StopCondition::And(items) => items.iter().all(|item| item.evaluate(state)),
+            StopCondition::Or(items) => items.iter().any(|item| item.evaluate(state)),
+        }
+    }
+
+    fn uses_idle(&self) -> bool {
+        match self {
+            StopCondition::Idle(_) => true,
+            StopCondition::And(items) | StopCondition::Or(items) => {
+                items.iter().any(|item| item.uses_idle())
+            }
+            _ => false,
+        }
+    }
+}
+
+fn tokenize_stop_expression(input: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    for c in input.chars() {
+        if c == '(' || c == ')' || c.is_whitespace() {
+            if !current.is_empty() {
+                tokens.push(std::mem::take(&mut current));
+            }
+            if !c.is_whitespace() {
+                tokens.push(c.to_string());
+            }
+        } else {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+fn parse_stop_primitive(token: &str) -> Result<StopCondition, String> {
+    let (name, value) = match token.split_once(':') {
+        Some((name, value)) => (name, Some(value)),
+        None => (token, None),
+    };
+    let number = |value: Option<&str>| -> Result<u64, String> {
+        value
+            .ok_or_else(|| format!("`{name}` needs a value, e.g. `{name}:3`"))?
+            .parse::<u64>()
+            .map_err(|_| format!("invalid number in `{token}`"))
+    };
+    let count = |value: Option<&str>| -> Result<u32, String> {
+        u32::try_from(number(value)?).map_err(|_| format!("`{token}` is too large"))
+    };
+    match name.to_ascii_lowercase().as_str() {
+        "token" => Ok(StopCondition::Token),
+        "iterations" => Ok(StopCondition::Iterations(count(value)?)),
+        "seconds" => Ok(StopCondition::Seconds(number(value)?)),
+        "idle" => Ok(StopCondition::Idle(count(value)?)),
+        "cost" => Err(format!(
+            "`{token}` is not supported: runners do not report cost (use --max-total-tokens)"
+        )),
+        "file" => match value {
+            Some(path) if !path.is_empty() => Ok(StopCondition::File(PathBuf::from(path))),
+            _ => Err("`file` needs a path, e.g. `file:ralph/DONE`".to_string()),
+        },
+        _ => Err(format!(
+            "unknown stop condition `{token}` (expected token, iterations:N, seconds:N, idle:N, file:PATH)"
+        )),
+    }
+}
+
+fn parse_stop_or(tokens: &[String], pos: &mut usize) -> Result<StopCondition, String> {
+    let mut items = vec![parse_stop_and(tokens, pos)?];
+    while tokens.get(*pos).is_some_and(|t| t.eq_ignore_ascii_case("or")) {
+        *pos += 1;
+        items.push(parse_stop_and(tokens, pos)?);
+    }
+    Ok(if items.len() == 1 {
+        items.remove(0)
+    } else {
+        StopCondition::Or(items)
+    })
+}
+
+fn parse_stop_and(tokens: &[String], pos: &mut usize) -> Result<StopCondition, String> {
+    let mut items = vec![parse_stop_atom(tokens, pos)?];
+    while tokens.get(*pos).is_some_and(|t| t.eq_ignore_ascii_case("and")) {
+        *pos += 1;
+        items.push(parse_stop_atom(tokens, pos)?);
+    }
+    Ok(if items.len() == 1 {
+        items.remove(0)
+    } else {
+        StopCondition::And(items)
+    })
+}
+
+fn parse_stop_atom(tokens: &[String], pos: &mut usize) -> Result<StopCondition, String> {
+    let token = tokens
+        .get(*pos)
+        .ok_or_else(|| "unexpected end of expression".to_string())?;
+    *pos += 1;
+    if token == "(" {
+        let inner = parse_stop_or(tokens, pos)?;
+        if tokens.get(*pos).map(String::as_str) != Some(")") {
+            return Err("missing `)`".to_string());
+        }
+        *pos += 1;
+        return Ok(inner);
+    }
+    if token == ")" || token.eq_ignore_ascii_case("and") || token.eq_ignore_ascii_case("or") {
+        return Err(format!("unexpected `{token}`"));
+    }
+    parse_stop_primitive(token)
+}
+
+fn parse_stop_expression(input: &str) -> Result<StopCondition, String> {
+    let tokens = tokenize_stop_expression(input);
+    let mut pos = 0;
+    let condition = parse_stop_or(&tokens, &mut pos)?;
+    if let Some(extra) = tokens.get(pos) {
+        return Err(format!("unexpected `{extra}`"));
+    }
+    Ok(condition)
+}
+
+fn has_merge_conflict(cwd: &Path) -> bool {
+    let status = run_command_output("git", &["status", "--porcelain"], cwd).unwrap_or_default();
+    let conflicted = status.lines().any(|line| {
+        matches!(
+            line.get(..2),
+            Some("UU" | "AA" | "DD" | "AU" | "UA" | "DU" | "UD")
+        )
+    });
+    conflicted || run_command_success("git", &["rev-parse", "-q", "--verify", "MERGE_HEAD"], cwd)
+}
+
+fn run_shell_command(command: &str, cwd: &Path) -> io::Result<Output> {
+    Command::new("sh")
+        .args(["-c", command])
+        .current_dir(cwd)
+        .output()
+}
+
+fn file_hash(path: &Path) -> Option<u64> {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+    let content = std::fs::read(path).ok()?;
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+fn worktree_fingerprint(cwd: &Path) -> String {
+    let head = run_command_output("git", &["rev-parse", "HEAD"], cwd).unwrap_or_default();
+    let status = run_command_output("git", &["status", "--porcelain"], cwd).unwrap_or_default();
+    format!("{head}\n{status}")
+}
+
+/// SplitMix64: tiny, and reproducible for a given seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform-ish value in `0..=max`.
+    fn up_to(&mut self, max: u64) -> u64 {
+        match max.checked_add(1) {
+            Some(bound) => self.next_u64() % bound,
+            None => self.next_u64(),
+        }
+    }
+}
+
+fn random_seed() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    nanos ^ (u64::from(std::process::id()) << 32)
+}
+
+fn default_runner_timeout(runner: &str) -> u64 {
+    match runner {
+        "codex" | "sdk" => 2 * 60 * 60,
+        "claude" => 60 * 60,
+        _ => 30 * 60,
+    }
+}
+
+fn looks_rate_limited(stderr: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(stderr).to_ascii_lowercase();
+    [
+        "rate limit",
+        "rate_limit",
+        "ratelimit",
+        "too many requests",
+        "status 429",
+        "http 429",
+        "error 429",
+    ]
+    .iter()
+    .any(|signature| text.contains(signature))
+}
+
+/// Zero for an isolated hit, then doubles per consecutive hit up to 16x the base.
+fn rate_limit_backoff(base: u64, hits: u32) -> u64 {
+    if hits < 2 {
+        return 0;
+    }
+    base.saturating_mul(1 << (hits - 2).min(4))
+}
+
+/// Appends `--retry-note` to the prompt on the first retry of an iteration.
+fn add_retry_note(prompt: &mut String, attempt: u32, note: &str) {
+    if attempt == 1 && !note.trim().is_empty() {
+        prompt.push_str(&format!("\n\n# Retry\n\n{}\n", note.trim()));
+    }
+}
+
+/// The signal that terminated a process; always `None` off Unix.
+#[cfg(unix)]
+fn exit_signal(status: &ExitStatus) -> Option<i32> {
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn exit_signal(_status: &ExitStatus) -> Option<i32> {
+    None
+}
+
+fn runner_failure_reason(status: &ExitStatus) -> String {
+    match (status.code(), exit_signal(status)) {
+        (_, Some(9)) => "runner killed by signal 9 (SIGKILL, likely out of memory)".to_string(),
+        (_, Some(signal)) => format!("runner killed by signal {signal}"),
+        (Some(code), None) => format!("runner exited with code {code}"),
+        (None, None) => "runner exited abnormally".to_string(),
+    }
+}
+
+fn has_arg(args: &[String], needle: &str) -> bool {
+    args.iter().any(|arg| arg == needle)
+}
+
+/// Scripted in-process runner for `--runner mock`. The script (`--mock-script` or
+/// `RALPH_MOCK_SCRIPT`) holds `---`-separated outputs, one per iteration (the last repeats);
+/// a leading `exit: N` line sets that iteration's exit code, clamped to 0..=255. Without a
+/// script every iteration prints its index.
+#[cfg(feature = "mock-runner")]
+fn run_mock(iteration: u32, script: Option<&Path>) -> io::Result<Output> {
+    let path = script
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("RALPH_MOCK_SCRIPT").map(PathBuf::from));
+    let script = match path {
+        Some(path) => std::fs::read_to_string(path)?,
+        None => String::new(),
+    };
+    let sections: Vec<&str> = script.split("\n---\n").collect();
+    let section = sections[(iteration as usize - 1).min(sections.len() - 1)];
+    let (code, mut stdout) = match section.split_once('\n') {
+        Some((first, rest)) if first.starts_with("exit:") => {
+            let code: i32 = first["exit:".len()..].trim().parse().unwrap_or(1);
+            (code.clamp(0, 255), rest.to_string())
+        }
+        _ if section.is_empty() => (0, format!("mock iteration {iteration}\n")),
+        _ => (0, section.to_string()),
+    };
+    if !stdout.ends_with('\n') {
+        stdout.push('\n');
+    }
+    #[cfg(unix)]
+    let status = ExitStatus::from_raw(code << 8);
+    #[cfg(windows)]
+    let status = std::os::windows::process::ExitStatusExt::from_raw(code as u32);
+    Ok(Output {
+        status,
+        stdout: stdout.into_bytes(),
+        stderr: Vec::new(),
+    })
+}
+
+fn run_generic(runner: &str, call: &Invocation<'_>, options: &RunnerOptions) -> io::Result<Output> {
+    let mut cmd = Command::new(runner);
+    if !call.model.is_empty() {
+        cmd.args(["--model", call.model]);
+    }
+    if !call.runner_args.is_empty() {
+        cmd.args(call.runner_args);
+    }
+    cmd.envs(options.env.iter().map(|(key, value)| (key, value)));
+    cmd.args(&options.prompt_flags).arg(call.prompt);
+    let timeout = options.timeout;
+    run_process_with_timeout(cmd, ProcessInput::Empty, timeout, true, true, options.kill_grace)
+}
+
+fn run_claude(call: &Invocation<'_>, options: &RunnerOptions) -> io::Result<Output> {
+    let mut cmd = Command::new("claude");
+    cmd.args(["--print", "--output-format", "json"]);
+    if !call.model.is_empty() {
+        cmd.args(["--model", call.model]);
+    }
+    if options.yolo && !has_arg(call.runner_args, "--dangerously-skip-permissions") {
+        cmd.arg("--dangerously-skip-permissions");
+    }
+    if let Some(spec) = options.specialization.as_deref()
+        && !spec.trim().is_empty()
+    {
+        cmd.args(["--append-system-prompt", spec]);
+    }
+    cmd.envs(options.env.iter().map(|(key, value)| (key, value)));
+    cmd.args(call.runner_args);
+    let mut output = run_process_with_timeout(
+        cmd,
+        ProcessInput::Text(call.prompt),
+        options.timeout,
+        true,
+        true,
+        options.kill_grace,
+    )?;
+    if let Ok(value) = serde_json::from_slice::<Value>(&output.stdout)
+        && let Some(result) = value.get("result").and_then(|v| v.as_str())
+    {
+        output.stdout = result.as_bytes().to_vec();
+    }
+    Ok(output)
+}
+
+fn run_sdk(call: &Invocation<'_>, options: &RunnerOptions) -> io::Result<Output> {
+    let prompt_path = write_temp_file("ralph-prompt", call.prompt)?;
+    let python = &options.sdk_python;
+    let mut cmd = Command::new(&python[0]);
+    cmd.args(&python[1..]);
+    cmd.args([
+        "scripts/ralph_agent.py",
+        "--prompt-file",
+        prompt_path.to_string_lossy().as_ref(),
+        "--model",
+        call.model,
+        "--max-turns",
+        &options.sdk_max_turns.to_string(),
+        "--reasoning-effort",
+        call.effort,
+    ]);
+    if let Some(spec) = options.specialization.as_deref()
+        && !spec.trim().is_empty()
+    {
+        cmd.args(["--specialization", spec]);
+    }
+    cmd.envs(options.env.iter().map(|(key, value)| (key, value)));
+    let timeout = options.timeout;
+    run_process_with_timeout(cmd, ProcessInput::Empty, timeout, true, true, options.kill_grace)
+}
+
+fn sdk_imports_agents(python: &[String]) -> bool {
+    Command::new(&python[0])
+        .args(&python[1..])
+        .args(["-c", "import agents"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+fn resolve_sdk_python(explicit: Option<&str>) -> io::Result<Vec<String>> {
+    if let Some(explicit) = explicit {
+        let python: Vec<String> = explicit.split_whitespace().map(str::to_string).collect();
+        if python.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--sdk-python must not be empty",
+            ));
+        }
+        ensure_runner(&python[0])?;
+        if !sdk_imports_agents(&python) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{explicit} cannot import the agents SDK (pip install openai-agents)"),
+            ));
+        }
+        return Ok(python);
+    }
+    if which::which("uv").is_ok() {
+        return Ok(vec![
+            "uv".to_string(),
+            "run".to_string(),
+            "python".to_string(),
+        ]);
+    }
+    for candidate in ["python3", "python"] {
+        let python = vec![candidate.to_string()];
+        if which::which(candidate).is_ok() && sdk_imports_agents(&python) {
+            eprintln!("[ralph] uv not found; running the SDK agent with {candidate}");
+            return Ok(python);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "Runner not found: uv (and no python3/python with the agents SDK installed; use --sdk-python)",
+    ))
+}
+
+fn ensure_runner(runner: &str) -> io::Result<()> {
+    let found = which::which(runner).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Runner not found: {runner}"),
+        )
+    })?;
+    let _ = found;
+    Ok(())
+}
+
+/// Flags that make no sense in a `--config` file.
+const CONFIG_EXCLUDED: [&str; 4] = ["help", "version", "config", "config-schema"];
+
+/// The flags a `--config` file may set, keyed by their long name.
+fn config_args() -> Vec<clap::Arg> {
+    Args::command()
+        .get_arguments()
+        .filter(|arg| !arg.is_hide_set())
+        .filter(|arg| arg.get_long().is_some_and(|long| !CONFIG_EXCLUDED.contains(&long)))
+        .cloned()
+        .collect()
+}
+
+/// JSON schema type of a single flag value, derived from its value parser.
+fn config_value_schema(arg: &clap::Arg) -> Value {
+    let parser = arg.get_value_parser();
+    let choices: Vec<String> = arg
+        .get_possible_values()
+        .iter()
+        .map(|value| value.get_name().to_string())
+        .collect();
+    let is_duration = arg
+        .get_value_names()
+        .is_some_and(|names| names.iter().any(|name| name == "DURATION"));
+    let type_id = parser.type_id();
+    if is_duration {
+        serde_json::json!({
+            "type": ["integer", "string"],
+            "description": "seconds, or a duration such as 90s, 30m or 1h30m",
+        })
+    } else if !choices.is_empty() && type_id != TypeId::of::<bool>() {
+        serde_json::json!({ "type": "string", "enum": choices })
+    } else if [TypeId::of::<u64>(), TypeId::of::<u32>(), TypeId::of::<usize>()]
+        .iter()
+        .any(|id| type_id == *id)
+    {
+        serde_json::json!({ "type": "integer", "minimum": 0 })
+    } else if type_id == TypeId::of::<bool>() {
+        serde_json::json!({ "type": "boolean" })
+    } else {
+        serde_json::json!({ "type": "string" })
+    }
+}
+
+fn config_schema() -> Value {
+    let mut properties = serde_json::Map::new();
+    for arg in config_args() {
+        let long = arg.get_long().unwrap_or_default();
+        let item = config_value_schema(&arg);
+        let mut schema = match arg.get_action() {
+            clap::ArgAction::SetTrue => serde_json::json!({ "type": "boolean" }),
+            clap::ArgAction::Append => serde_json::json!({ "type": "array", "items": item }),
+            _ => item,
+        };
+        if let Some(help) = arg.get_help() {
+            schema["description"] = serde_json::json!(help.to_string());
+        }
+        if let Some(default) = arg.get_default_values().first()
+            && arg.get_action().takes_values()
+        {
+            let default = default.to_string_lossy();
+            schema["default"] = match default.parse::<u64>() {
+                Ok(number) => serde_json::json!(number),
+                Err(_) => serde_json::json!(default),
+            };
+        }
+        properties.insert(long.replace('-', "_"), schema);
+    }
+    serde_json::json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "ralph --config file",
+        "description": "Flat TOML keys mirror the long flags with dashes replaced by underscores.",
+        "type": "object",
+        "additionalProperties": false,
+        "properties": properties,
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum ConfigValue {
+    Bool(bool),
+    Scalar(String),
+    List(Vec<String>),
+}
+
+/// Reads one string, number or bare word from the start of `text`; returns it and the rest.
+fn take_config_scalar(text: &str) -> Result<(String, &str), String> {
+    if let Some(rest) = text.strip_prefix('\'') {
+        let end = rest.find('\'').ok_or("unterminated string")?;
+        return Ok((rest[..end].to_string(), &rest[end + 1..]));
+    }
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' => return Ok((value, &rest[index + 1..])),
+                '\\' => match chars.next().map(|(_, escaped)| escaped) {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some(escaped @ ('"' | '\\')) => value.push(escaped),
+                    _ => return Err("unsupported escape in string".to_string()),
+                },
+                _ => value.push(c),
+            }
+        }
+        return Err("unterminated string".to_string());
+    }
+    let end = text
+        .find(|c: char| c.is_whitespace() || matches!(c, ',' | ']' | '#'))
+        .unwrap_or(text.len());
+    if end == 0 {
+        return Err("missing value".to_string());
+    }
+    Ok((text[..end].to_string(), &text[end..]))
+}
+
+fn parse_config_value(text: &str) -> Result<ConfigValue, String> {
+    let (value, rest) = if let Some(mut rest) = text.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                break (ConfigValue::List(items), after);
+            }
+            let (item, after) = take_config_scalar(rest)?;
+            items.push(item);
+            rest = after.trim_start();
+            rest = rest.strip_prefix(',').unwrap_or(rest);
+            if rest.is_empty() {
+                return Err("unterminated array (arrays must fit on one line)".to_string());
+            }
+        }
+    } else {
+        let quoted = text.starts_with(['"', '\'']);
+        let (value, rest) = take_config_scalar(text)?;
+        match value.as_str() {
+            "true" | "false" if !quoted => (ConfigValue::Bool(value == "true"), rest),
+            _ => (ConfigValue::Scalar(value), rest),
+        }
+    };
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(format!("unexpected `{rest}` after value"));
+    }
+    Ok(value)
+}
+
+/// Turns a flat TOML `--config` file into the equivalent command-line flags.
+fn config_file_args(content: &str) -> Result<Vec<OsString>, String> {
+    let known = config_args();
+    let mut flags = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let at = |err: String| format!("line {}: {err}", index + 1);
+        if line.starts_with('[') {
+            return Err(at("tables are not supported; use top-level keys".to_string()));
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| at("expected `key = value`".to_string()))?;
+        let key = key.trim().trim_matches('"');
+        let long = key.replace('_', "-");
+        let arg = known
+            .iter()
+            .find(|arg| arg.get_long() == Some(long.as_str()))
+            .ok_or_else(|| at(format!("unknown key `{key}`")))?;
+        let value = parse_config_value(value.trim()).map_err(at)?;
+        let flag = format!("--{long}");
+        match (arg.get_action(), value) {
+            (clap::ArgAction::SetTrue, ConfigValue::Bool(set)) => {
+                if set {
+                    flags.push(flag.into());
+                }
+            }
+            (clap::ArgAction::SetTrue, _) => {
+                return Err(at(format!("`{key}` must be true or false")));
+            }
+            (clap::ArgAction::Append, ConfigValue::List(items)) if items.is_empty() => {
+                if arg.get_num_args().is_some_and(|range| range.min_values() == 0) {
+                    flags.push(flag.into());
+                }
+            }
+            (clap::ArgAction::Append, ConfigValue::List(items)) => {
+                flags.extend(items.iter().map(|item| format!("{flag}={item}").into()));
+            }
+            (_, ConfigValue::List(_)) => return Err(at(format!("`{key}` takes a single value"))),
+            (_, ConfigValue::Bool(set)) => flags.push(format!("{flag}={set}").into()),
+            (_, ConfigValue::Scalar(value)) => flags.push(format!("{flag}={value}").into()),
+        }
+    }
+    Ok(flags)
+}
+
+/// Parses the command line, first applying any `--config` file. Later occurrences of a
+/// single-value flag win, so command-line values override the file; list flags accumulate.
+fn parse_args(mut argv: Vec<OsString>) -> io::Result<Args> {
+    let mut config = None;
+    let mut rest = argv.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        let text = arg.to_string_lossy();
+        if text == "--config" {
+            config = rest.next().map(PathBuf::from);
+        } else if let Some(path) = text.strip_prefix("--config=") {
+            config = Some(PathBuf::from(path));
+        } else if text == "--" {
+            break;
+        }
+    }
+    if let Some(path) = config {
+        let content = std::fs::read_to_string(&path).map_err(|err| {
+            io::Error::new(err.kind(), format!("Cannot read config {}: {err}", path.display()))
+        })?;
+        let flags = config_file_args(&content).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid config {}: {err}", path.display()),
+            )
+        })?;
+        let at = argv.len().min(1);
+        argv.splice(at..at, flags);
+    }
+    let matches = Args::command()
+        .args_override_self(true)
+        .try_get_matches_from(argv)
+        .unwrap_or_else(|err| err.exit());
+    Ok(Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit()))
+}
+
+fn status_line(label: &str, path: &Path) {
+    let state = if path.exists() { "present" } else { "missing" };
+    println!("{label:<16} {state:<8} {}", path.display());
+}
+
+fn print_status(cwd: &Path, paths: &[(&str, &Path)], linear: &LinearOptions) {
+    println!("{:<16} {}", "repo", cwd.display());
+    if let Some(branch) = current_git_branch(cwd) {
+        let dirty = if git_has_uncommitted_changes(cwd) {
+            " (uncommitted changes)"
+        } else {
+            ""
+        };
+        println!("{:<16} {branch}{dirty}", "branch");
+    }
+    for (label, path) in paths {
+        status_line(label, path);
+    }
+    status_line("goal cache", &goal_cache_path(cwd));
+    match read_run_summary(cwd) {
+        Some(summary) => println!(
+            "{:<16} {} after {} iterations in {}s (exit {}, {})",
+            "last run",
+            summary.stop_reason.as_deref().unwrap_or("unknown"),
+            summary.iterations,
+            summary.duration_secs,
+            summary.exit_code,
+            format_utc(summary.started_at)
+        ),
+        None => println!("{:<16} none recorded", "last run"),
+    }
+    let servers: Vec<String> = list_mcp_servers()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    let servers = if servers.is_empty() {
+        "none".to_string()
+    } else {
+        servers.join(", ")
+    };
+    println!("{:<16} {servers}", "mcp servers");
+    let token = if linear_token(linear.workspace.as_deref()).is_some() {
+        "present"
+    } else {
+        "missing"
+    };
+    println!("{:<16} {token}", "linear token");
+}
+
+fn main() -> io::Result<()> {
+    let exit_code = run(parse_args(env::args_os().collect())?, env::current_dir()?)?;
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+    Ok(())
+}
+
+/// Runs ralph from `dir` and returns the process exit code.
+fn run(args: Args, dir: PathBuf) -> io::Result<i32> {
+    if let Some(home) = args.codex_home.clone() {
+        let _ = CODEX_HOME_OVERRIDE.set(home);
+    }
+    if args.config_schema {
+        let schema = serde_json::to_string_pretty(&config_schema()).map_err(io::Error::other)?;
+        println!("{schema}");
+        return Ok(0);
+    }
+    if args.supervise {
+        return supervise(&args);
+    }
+    QUIET.store(args.dump_last_message, Ordering::Relaxed);
+    if let Some(path) = args.replay_log.as_deref() {
+        let stop_tokens = load_stop_tokens(&args.stop_token, args.stop_token_file_list.as_deref())?;
+        return replay_log(path, &stop_tokens).map(|()| 0);
+    }
+    if args.status {
+        // Inspect the checkout as-is: no worktree is created and no template is fetched.
+        let [template, prd, progress, log] = state_paths(&args, &dir);
+        print_status(
+            &dir,
+            &[
+                ("prompt template", &template),
+                ("prd", &prd),
+                ("progress", &progress),
+                ("log", &log),
+            ],
+            &LinearOptions::from_args(&args),
+        );
+        return Ok(0);
+    }
+    let _temp_cleanup = (!args.keep_temp).then_some(TempFileCleanup);
+    let mut _worktree_cleanup = None;
+    let cwd = match args.worktree.as_deref() {
+        Some(path) => {
+            let repo = dir;
+            let worktree = create_worktree(&repo, path, args.worktree_base.as_deref())?;
+            env::set_current_dir(&worktree)?;
+            if args.remove_worktree {
+                _worktree_cleanup = Some(WorktreeCleanup {
+                    repo,
+                    path: worktree.clone(),
+                });
+            }
+            worktree
+        }
+        None => dir,
+    };
+
+    let [template_path, prd_path, progress_path, log_path] = state_paths(&args, &cwd);
+    let linear = LinearOptions::from_args(&args);
+
+    let runner = args.runner;
+    let model = args.model;
+    let reasoning_effort = args.reasoning_effort;
+    let iterations = if args.once { 1 } else { args.iterations };
+    let sleep_secs = args.sleep;
+    let max_seconds = args.max_seconds;
+    let kill_grace = (args.timeout_kill_grace > 0)
+        .then(|| Duration::from_secs(args.timeout_kill_grace));
+    let runner_timeout = match args.runner_timeout {
+        Some(0) => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+        None => Some(Duration::from_secs(default_runner_timeout(&runner))),
+    };
+    let context_options = ContextOptions {
+        file_limit: args.context_file_limit,
+        diff_max_files: args.diff_context_max_files,
+        compress: args.prompt_compress,
+        binary_skip: args.context_binary_skip,
+        readme_globs: args.context_readme_glob.clone(),
+        priority: args.context_priority.clone(),
+        proxy: args.http_proxy.clone(),
+        git_author: args.context_git_author.clone(),
+        env_vars: args.context_env.clone(),
+        redact_prefixes: args.redact_log.clone().unwrap_or_default(),
+        command_timeout: (args.context_command_timeout > 0)
+            .then(|| Duration::from_secs(args.context_command_timeout)),
+        linear,
+    };
+    if let Some(workspace) = args.linear_workspace.as_deref()
+        && linear_token(Some(workspace)).is_none()
+    {
+        eprintln!("[ralph] no Linear token found for workspace {workspace}");
+    }
+    let context_cache_ttl = if args.context_cache_ttl > 0 {
+        Some(Duration::from_secs(args.context_cache_ttl))
     } else {
         None
     };
@@ -1113,22 +5038,16 @@ fn main() -> io::Result<()> {
         .context_log
         .clone()
         .or_else(|| Some(cwd.join("ralph/context.txt")));
-    let prompt_template = args
-        .prompt_template
-        .unwrap_or_else(|| env_or_path("RALPH_PROMPT_TEMPLATE", default_template));
-    let prd_path = args.prd.unwrap_or_else(|| env_or_path("RALPH_PRD", default_prd));
-    let progress_path = args
-        .progress
-        .unwrap_or_else(|| env_or_path("RALPH_PROGRESS", default_progress));
-    let log_path = args
-        .log
-        .unwrap_or_else(|| env_or_path("RALPH_LOG", default_log));
+    let prompt_template = match args.prompt_template_url.as_deref() {
+        Some(url) => fetch_remote_template(url, &template_path, args.http_proxy.as_deref())?,
+        None => template_path,
+    };
     let stop_token = args.stop_token;
-    let prompt_flag = args.prompt_flag;
-    let yolo = !args.no_yolo;
+    let stop_tokens = load_stop_tokens(&stop_token, args.stop_token_file_list.as_deref())?;
+    let blocked_token = args.blocked_token.clone().filter(|token| !token.trim().is_empty());
     let use_sdk = runner == "sdk";
 
-    if args.ensure_mcp {
+    if args.ensure_mcp && !args.no_ensure_mcp {
         let _ = ensure_openai_docs_mcp();
     }
 
@@ -1138,11 +5057,23 @@ fn main() -> io::Result<()> {
             println!("No MCP servers configured.");
         } else {
             println!("Configured MCP servers:");
-            for name in servers {
-                println!("- {name}");
+            for (name, url) in servers {
+                if !args.check {
+                    println!("- {name}");
+                    continue;
+                }
+                match url.as_deref() {
+                    Some(url) if url.starts_with("http") => {
+                        match check_mcp_server(url, args.http_proxy.as_deref()) {
+                            Ok(status) => println!("- {name}: reachable (HTTP {status})"),
+                            Err(err) => println!("- {name}: unreachable ({err})"),
+                        }
+                    }
+                    _ => println!("- {name}: not an HTTP server; skipped"),
+                }
             }
         }
-        return Ok(());
+        return Ok(0);
     }
 
     let repo_name = cwd
@@ -1150,91 +5081,180 @@ fn main() -> io::Result<()> {
         .and_then(|name| name.to_str())
         .unwrap_or("repo");
 
+    if args.linear_create_issues_from_tasks {
+        let team = args.linear_team.as_deref().unwrap_or_default();
+        return create_linear_issues_from_tasks(&context_options.linear, &prd_path, team)
+            .map(|()| 0);
+    }
+
+    if args.dump_context {
+        print!("{}", collect_repo_context(repo_name, &cwd, &context_options));
+        return Ok(0);
+    }
+
+    let infer_schema: Option<Value> = match args.infer_schema.as_deref() {
+        Some(path) => {
+            let content = std::fs::read_to_string(path)?;
+            Some(serde_json::from_str(&content).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid JSON schema {}: {err}", path.display()),
+                )
+            })?)
+        }
+        None => None,
+    };
+
+    let sdk_python = if use_sdk {
+        resolve_sdk_python(args.sdk_python.as_deref())?
+    } else {
+        Vec::new()
+    };
+    let runner_options = RunnerOptions {
+        runner: runner.clone(),
+        model: model.clone(),
+        effort: reasoning_effort.clone(),
+        yolo: !args.no_yolo,
+        full_auto: args.full_auto,
+        sandbox_mode: args.codex_sandbox_mode,
+        specialization: args.specialization.clone(),
+        codex_json: args.codex_json,
+        timeout: runner_timeout,
+        kill_grace,
+        env: args.runner_env.clone(),
+        profile: args.codex_profile.clone(),
+        tools: args.codex_tool.clone(),
+        prompt_via: args.codex_prompt_via,
+        prompt_flags: args
+            .prompt_flag
+            .iter()
+            .flat_map(|flag| flag.split_whitespace())
+            .map(str::to_string)
+            .collect(),
+        sdk_max_turns: args.sdk_max_turns,
+        sdk_python,
+    };
+    let infer = |context: &str,
+                 feedback: Option<&str>,
+                 previous: Option<(String, String)>,
+                 candidates: u32| {
+        let request = GoalRequest {
+            context,
+            feedback,
+            previous,
+            candidates,
+            schema: infer_schema.as_ref(),
+            include_diff: args.infer_include_diff,
+        };
+        infer_goal(request, &runner_options)
+    };
+
     let mut goal = args.goal.unwrap_or_default();
     let mut next_action = args.next_action.unwrap_or_default();
     let mut inference_context: Option<String> = None;
 
     if args.infer_only {
-        if use_sdk {
-            ensure_runner("uv")?;
-        } else {
+        if !use_sdk {
             ensure_runner("codex")?;
         }
-        let context = prepare_inference_context(repo_name, &cwd, context_log.as_deref())?;
-        let mut result = if use_sdk {
-            infer_goal_with_sdk(
-                &context,
-                &model,
-                &reasoning_effort,
-                specialization,
-                None,
-                None,
-                args.sdk_max_turns,
-                runner_timeout,
-            )?
-        } else {
-            infer_goal_with_codex(
-                &context,
-                &model,
-                &reasoning_effort,
-                yolo,
-                specialization,
-                None,
-                None,
-                runner_timeout,
-                codex_json,
-            )?
-        };
-        if let Some((ultimate, action)) = result.clone() {
-            if looks_like_noise_cleanup(&action) {
-                let feedback = noise_cleanup_feedback();
-                result = if use_sdk {
-                    infer_goal_with_sdk(
-                        &context,
-                        &model,
-                        &reasoning_effort,
-                        specialization,
-                        Some(feedback),
-                        Some((ultimate, action)),
-                        args.sdk_max_turns,
-                        runner_timeout,
-                    )?
-                } else {
-                    infer_goal_with_codex(
-                        &context,
-                        &model,
-                        &reasoning_effort,
-                        yolo,
-                        specialization,
-                        Some(feedback),
-                        Some((ultimate, action)),
-                        runner_timeout,
-                        codex_json,
-                    )?
-                };
-            }
+        let context = prepare_inference_context(
+            repo_name,
+            &cwd,
+            context_log.as_deref(),
+            context_cache_ttl,
+            &context_options,
+        )?;
+        let mut result = infer(&context, None, None, args.infer_candidates)?;
+        if let Some((ultimate, action)) = result.clone()
+            && looks_like_noise_cleanup(&action)
+        {
+            let feedback = noise_cleanup_feedback();
+            result = infer(&context, Some(feedback), Some((ultimate, action)), 1)?;
         }
-        if let Some((ultimate, action)) = result.clone() {
-            if looks_like_noise_cleanup(&action) {
-                let fallback = "Review README/PRD/Linear and pick a concrete code or test task; avoid cleanup unless it blocks tests.";
-                result = Some((ultimate, fallback.to_string()));
-            }
+        if let Some((ultimate, action)) = result.clone()
+            && looks_like_noise_cleanup(&action)
+        {
+            let fallback = "Review README/PRD/Linear and pick a concrete code or test task; avoid cleanup unless it blocks tests.";
+            result = Some((ultimate, fallback.to_string()));
         }
         if let Some((ultimate, action)) = result {
+            match write_goal_cache(&cwd, &ultimate, &action) {
+                Ok(()) => eprintln!("[ralph] cached goal in {}", goal_cache_path(&cwd).display()),
+                Err(err) => eprintln!("[ralph] could not cache goal: {err}"),
+            }
             let output = serde_json::json!({
                 "ultimate_goal": ultimate,
                 "next_action": action
             });
             println!("{output}");
-            return Ok(());
+            return Ok(0);
+        }
+        return Err(io::Error::other("Inference failed"));
+    }
+    if args.abort_on_uncommitted_at_start && !args.allow_dirty {
+        let dirty = dirty_paths_at_start(&cwd);
+        if !dirty.is_empty() {
+            return Err(io::Error::other(format!(
+                "working tree has uncommitted changes; commit or stash them, or pass --allow-dirty:\n{}",
+                truncate_string(&dirty.join("\n"), 2000)
+            )));
+        }
+    }
+    let mut _branch_restore = None;
+    if let Some(branch) = args.git_branch.as_deref() {
+        let original = current_git_branch(&cwd);
+        let stashed = switch_to_branch(&cwd, branch, args.stash)?;
+        if args.return_to_branch
+            && let Some(original) = original.filter(|name| name != branch && name != "HEAD")
+        {
+            _branch_restore = Some(BranchRestore {
+                cwd: cwd.clone(),
+                branch: original,
+                pop_stash: stashed,
+            });
+        } else if stashed {
+            status!("[ralph] stashed changes remain in `git stash list`");
+        }
+    }
+
+    let baseline = if args.preflight_commit {
+        let baseline = preflight_commit(&cwd)?;
+        if let Some(commit) = baseline.as_deref() {
+            status!("[ralph] baseline commit {commit}");
+        }
+        baseline
+    } else {
+        None
+    };
+
+    let uses_template = args.task_dir.is_none() && args.prompt_from_issue.is_none();
+    if uses_template
+        && prompt_template.is_file()
+        && std::fs::read_to_string(&prompt_template)?.trim().is_empty()
+    {
+        if args.strict {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("prompt template {} is empty", prompt_template.display()),
+            ));
         }
-        return Err(io::Error::new(io::ErrorKind::Other, "Inference failed"));
+        eprintln!(
+            "[ralph] prompt template {} is empty; regenerating",
+            prompt_template.display()
+        );
+        std::fs::remove_file(&prompt_template)?;
     }
-    if !prompt_template.is_file() {
+    if uses_template && !prompt_template.is_file() {
+        if goal.is_empty()
+            && next_action.is_empty()
+            && let Some((cached_goal, cached_action)) = read_goal_cache(&cwd)
+        {
+            status!("[ralph] using cached goal from {}", goal_cache_path(&cwd).display());
+            goal = cached_goal;
+            next_action = cached_action;
+        }
         if goal.is_empty() || next_action.is_empty() {
-            if use_sdk {
-                ensure_runner("uv")?;
-            } else {
+            if !use_sdk {
                 ensure_runner("codex")?;
             }
             if inference_context.is_none() {
@@ -1242,39 +5262,20 @@ fn main() -> io::Result<()> {
                     repo_name,
                     &cwd,
                     context_log.as_deref(),
+                    context_cache_ttl,
+                    &context_options,
                 )?);
             }
             let context = inference_context.as_ref().unwrap();
-            let mut proposal = if use_sdk {
-                infer_goal_with_sdk(
-                    context,
-                    &model,
-                    &reasoning_effort,
-                    specialization,
-                    None,
-                    None,
-                    args.sdk_max_turns,
-                    runner_timeout,
-                )?
-            } else {
-                infer_goal_with_codex(
-                    context,
-                    &model,
-                    &reasoning_effort,
-                    yolo,
-                    specialization,
-                    None,
-                    None,
-                    runner_timeout,
-                    codex_json,
-                )?
-            }
-            .unwrap_or_else(|| {
-                (
-                    format!("Bootstrap {repo_name} with a PRD, progress log, and initial tasks."),
-                    "Draft PRD and create initial tasks in Linear.".to_string(),
-                )
-            });
+            let mut proposal = infer(context, None, None, args.infer_candidates)?
+                .unwrap_or_else(|| {
+                    (
+                        format!(
+                            "Bootstrap {repo_name} with a PRD, progress log, and initial tasks."
+                        ),
+                        "Draft PRD and create initial tasks in Linear.".to_string(),
+                    )
+                });
 
             let mut auto_attempts = 0;
             while next_action.is_empty()
@@ -1283,30 +5284,7 @@ fn main() -> io::Result<()> {
             {
                 let feedback = noise_cleanup_feedback();
                 let previous = Some((proposal.0.clone(), proposal.1.clone()));
-                let refined = if use_sdk {
-                    infer_goal_with_sdk(
-                        context,
-                        &model,
-                        &reasoning_effort,
-                        specialization,
-                        Some(feedback),
-                        previous,
-                        args.sdk_max_turns,
-                        runner_timeout,
-                    )?
-                } else {
-                    infer_goal_with_codex(
-                        context,
-                        &model,
-                        &reasoning_effort,
-                        yolo,
-                        specialization,
-                        Some(feedback),
-                        previous,
-                        runner_timeout,
-                        codex_json,
-                    )?
-                };
+                let refined = infer(context, Some(feedback), previous, 1)?;
                 if let Some(next) = refined {
                     proposal = next;
                 }
@@ -1319,13 +5297,13 @@ fn main() -> io::Result<()> {
 
             loop {
                 if goal.is_empty() {
-                    println!("[ralph] Proposed ultimate goal: {}", proposal.0);
+                    status!("[ralph] Proposed ultimate goal: {}", proposal.0);
                     if prompt_yes_no("[ralph] Use this ultimate goal?")? {
                         goal = proposal.0.clone();
                     }
                 }
                 if next_action.is_empty() {
-                    println!("[ralph] Proposed next action: {}", proposal.1);
+                    status!("[ralph] Proposed next action: {}", proposal.1);
                     if prompt_yes_no("[ralph] Use this next action?")? {
                         next_action = proposal.1.clone();
                     }
@@ -1336,30 +5314,7 @@ fn main() -> io::Result<()> {
                 }
 
                 let feedback = prompt_for_feedback()?;
-                let refined = if use_sdk {
-                    infer_goal_with_sdk(
-                        context,
-                        &model,
-                        &reasoning_effort,
-                        specialization,
-                        Some(&feedback),
-                        Some(proposal.clone()),
-                        args.sdk_max_turns,
-                        runner_timeout,
-                    )?
-                } else {
-                    infer_goal_with_codex(
-                        context,
-                        &model,
-                        &reasoning_effort,
-                        yolo,
-                        specialization,
-                        Some(&feedback),
-                        Some(proposal.clone()),
-                        runner_timeout,
-                        codex_json,
-                    )?
-                };
+                let refined = infer(context, Some(&feedback), Some(proposal.clone()), 1)?;
                 match refined {
                     Some(pair) => proposal = pair,
                     None => {
@@ -1382,11 +5337,17 @@ fn main() -> io::Result<()> {
         let next_action_text = if next_action.is_empty() {
             "Next action: (unspecified)".to_string()
         } else {
-            format!("{next_action}")
+            next_action.to_string()
         };
-        let template = default_template_content()
+        let mut template = default_template_content()
             .replace("{{GOAL}}", &goal_text)
             .replace("{{NEXT_ACTION}}", &next_action_text);
+        if args.progress_format == ProgressFormat::Jsonl {
+            template = template.replace(
+                "2) Update the progress log at {{PROGRESS}} with status and next steps.",
+                "2) Append a JSON line to the progress log at {{PROGRESS}}:\n   {\"ts\": <unix seconds>, \"iteration\": <n>, \"status\": \"...\", \"note\": \"next steps\"}",
+            );
+        }
         ensure_file(&prompt_template, &template)?;
     }
 
@@ -1405,125 +5366,764 @@ fn main() -> io::Result<()> {
     }
 
     if !progress_path.is_file() {
-        let progress = format!(
-            "Initialized Ralph progress log for {repo_name}.\n"
-        );
+        let progress = match args.progress_format {
+            ProgressFormat::Text => format!("Initialized Ralph progress log for {repo_name}.\n"),
+            ProgressFormat::Jsonl => progress_entry(
+                0,
+                "initialized",
+                &format!("Initialized Ralph progress log for {repo_name}."),
+            ),
+        };
         ensure_file(&progress_path, &progress)?;
     }
 
-    if runner == "sdk" {
-        ensure_runner("uv")?;
-    } else {
+    if runner != "sdk" && !(cfg!(feature = "mock-runner") && runner == "mock") {
         ensure_runner(&runner)?;
     }
+    let state_dir = cwd.join("ralph");
+    if let Some(reason) = low_disk_space(&state_dir, &cwd, args.min_free_space) {
+        return Err(io::Error::other(format!("{reason}; aborting before the run")));
+    }
+
+    let mut resume_id = args.resume_id.clone();
+    if args.resume_if_session_exists && runner == "codex" && !args.resume && resume_id.is_none() {
+        match latest_codex_session(&cwd, Duration::from_secs(7 * 24 * 60 * 60)) {
+            Some(id) => {
+                status!("[ralph] resuming codex session {id}");
+                resume_id = Some(id);
+            }
+            None => status!("[ralph] no recent codex session for this repo; starting fresh"),
+        }
+    }
+
+    let stop_when = match args.stop_when.as_deref() {
+        Some(expr) => Some(parse_stop_expression(expr).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid --stop-when: {err}"))
+        })?),
+        None => None,
+    };
+    if let Some(expr) = args.stop_when.as_deref()
+        && args.stop_after_first_success
+    {
+        status!(
+            "[ralph] --stop-after-first-success overrides --stop-when: token or ({expr})."
+        );
+    }
+    let mut idle_streak = 0;
+    let mut last_fingerprint = stop_when
+        .as_ref()
+        .filter(|condition| condition.uses_idle())
+        .map(|_| worktree_fingerprint(&cwd));
 
-    let mut prompt = load_prompt(&prompt_template, &prd_path, &progress_path)?;
-    if let Some(extra) = args.extra.as_deref() {
-        if !extra.trim().is_empty() {
-            prompt = format!("{extra}\n\n{prompt}");
+    let prd_ref = (!args.no_prd_ref).then_some(prd_path.as_path());
+    let progress_ref = (!args.no_progress_ref).then_some(progress_path.as_path());
+    let task_files = match args.task_dir.as_deref() {
+        Some(dir) => {
+            let files = list_task_files(dir)?;
+            if files.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No task files found in {}", dir.display()),
+                ));
+            }
+            status!("[ralph] {} task(s) queued from {}", files.len(), dir.display());
+            Some(files)
+        }
+        None => None,
+    };
+    let issue_template = match args.prompt_from_issue.as_deref() {
+        Some(issue) => {
+            let template = linear_issue_template(&context_options.linear, issue).ok_or_else(|| {
+                io::Error::other(format!("Could not fetch Linear issue {issue}"))
+            })?;
+            status!("[ralph] prompt from Linear issue {issue}");
+            Some(template)
+        }
+        None => None,
+    };
+    if args.validate_template {
+        match task_files.as_ref() {
+            Some(files) => {
+                for file in files {
+                    validate_template(file)?;
+                }
+            }
+            None if uses_template => validate_template(&prompt_template)?,
+            None => {}
         }
     }
+    let mut prompt = if task_files.is_some() {
+        String::new()
+    } else if let Some(template) = issue_template.as_deref() {
+        with_extra(
+            render_prompt(template, prd_ref, progress_ref, args.prompt_debug),
+            args.extra.as_deref(),
+        )
+    } else {
+        with_extra(
+            load_prompt(&prompt_template, prd_ref, progress_ref, args.prompt_debug)?,
+            args.extra.as_deref(),
+        )
+    };
+    if args.prompt_lint && task_files.is_none() {
+        lint_prompt(&prompt, &stop_token, "prompt");
+    }
+    let plan_path = cwd.join("ralph/plan.md");
+    if args.plan {
+        let context = prepare_inference_context(
+            repo_name,
+            &cwd,
+            context_log.as_deref(),
+            context_cache_ttl,
+            &context_options,
+        )?;
+        let plan_prompt = build_plan_prompt(&context, &prompt, &goal);
+        let plan_options = RunnerOptions {
+            sandbox_mode: Some(CodexSandbox::ReadOnly),
+            codex_json: false,
+            prompt_via: PromptVia::Stdin,
+            ..runner_options.clone()
+        };
+        let call = Invocation {
+            prompt: &plan_prompt,
+            model: &model,
+            effort: &reasoning_effort,
+            runner_args: &args.runner_arg,
+            resume_last: false,
+            resume_id: None,
+        };
+        let output = with_progress("planning", || run_agent(&call, &plan_options, None))?;
+        let plan = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !output.status.success() || plan.is_empty() {
+            return Err(io::Error::other("Plan pass produced no plan"));
+        }
+        if let Some(parent) = plan_path.parent() {
+            create_dir_all(parent)?;
+        }
+        std::fs::write(&plan_path, format!("{plan}\n"))?;
+        status!("[ralph] plan written to {}", plan_path.display());
+    }
+    let previous_run = if args.resume_context {
+        let review = read_run_summary(&cwd).map(|summary| render_run_review(&summary, &cwd));
+        if review.is_none() {
+            eprintln!("[ralph] no previous run summary found; starting without resume context.");
+        }
+        review
+    } else {
+        None
+    };
     let start = Instant::now();
+    let started_at = unix_timestamp();
+    let start_head = run_command_output("git", &["rev-parse", "HEAD"], &cwd);
     let mut stop_reason: Option<String> = None;
+    let mut exit_code = 0;
+    let mut iterations_run = 0;
+    let mut last_message: Vec<u8> = Vec::new();
+    let mut recovered_context: Option<String> = None;
+    let mut token_fired = false;
+    let mut run_error: Option<io::Error> = None;
+    let mut history: VecDeque<(u32, String)> = VecDeque::new();
+    let mut rate_limit_hits: u32 = 0;
+    let schedule = match args.schedule_file.as_deref() {
+        Some(path) => load_schedule(path)?,
+        None => HashMap::new(),
+    };
+    let mut stop_evaluation = StopEvaluation::default();
+    let seed = args.seed.unwrap_or_else(random_seed);
+    status!("[ralph] seed {seed}");
+    let mut rng = Rng(seed);
+    let mut codex_events = CodexEvents {
+        stop_field: args.json_stop_field.clone(),
+        tokens: args.tokens_used,
+        ..CodexEvents::default()
+    };
+    let mut events = args.event_socket.as_deref().and_then(connect_event_socket);
+    emit_event(
+        &mut events,
+        "run_start",
+        serde_json::json!({ "iterations": iterations, "runner": runner, "model": model }),
+    );
+
+    let state_files: Vec<&Path> = [
+        Some(prd_path.as_path()),
+        uses_template.then_some(prompt_template.as_path()),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
 
-    for i in 1..=iterations {
-        if max_seconds > 0 && start.elapsed().as_secs() >= max_seconds {
+    'iterations: for i in args.start_iteration..=iterations {
+        stop_evaluation.start(i);
+        let elapsed = start.elapsed().as_secs();
+        if max_seconds > 0
+            && stop_evaluation.record(
+                "max runtime",
+                format!("{elapsed}s of {max_seconds}s"),
+                elapsed >= max_seconds,
+            )
+        {
             stop_reason = Some(format!("reached max runtime ({max_seconds}s)"));
             break;
         }
-        println!("[ralph] iteration {i}/{iterations}");
-        let output = {
-            let result = if runner == "codex" {
-                run_codex(
-                    &prompt,
-                    &model,
-                    &reasoning_effort,
-                    &args.runner_arg,
-                    args.full_auto,
-                    yolo,
-                    args.resume,
-                    args.resume_id.as_deref(),
-                    specialization,
-                    codex_json,
-                    runner_timeout,
-                )
-            } else if use_sdk {
-                run_sdk(
-                    &prompt,
-                    &model,
-                    &reasoning_effort,
-                    specialization,
-                    args.sdk_max_turns,
-                    runner_timeout,
-                )
+        let low_disk = low_disk_space(&state_dir, &cwd, args.min_free_space);
+        if args.min_free_space > 0
+            && stop_evaluation.record(
+                "min free space",
+                low_disk.clone().unwrap_or_else(|| "ok".to_string()),
+                low_disk.is_some(),
+            )
+            && let Some(reason) = low_disk
+        {
+            stop_reason = Some(reason);
+            exit_code = 1;
+            break;
+        }
+        if let Some(files) = task_files.as_ref() {
+            let Some(task) = files.get(i as usize - 1) else {
+                stop_evaluation.record("task directory", "exhausted".to_string(), true);
+                stop_reason = Some("task directory exhausted".to_string());
+                break;
+            };
+            prompt = with_extra(
+                load_prompt(task, prd_ref, progress_ref, args.prompt_debug)?,
+                args.extra.as_deref(),
+            );
+            status!("[ralph] task {}", task.display());
+            if args.prompt_lint {
+                lint_prompt(&prompt, &stop_token, &task.display().to_string());
+            }
+        } else if args.reload_template && uses_template && i > 1 {
+            match load_prompt(&prompt_template, prd_ref, progress_ref, args.prompt_debug) {
+                Ok(reloaded) => prompt = with_extra(reloaded, args.extra.as_deref()),
+                Err(err) => eprintln!(
+                    "[ralph] failed to reload {}: {err}; reusing last prompt",
+                    prompt_template.display()
+                ),
+            }
+        }
+        status!("[ralph] iteration {i}/{iterations}");
+        let warmup = i <= args.warmup_iterations;
+        if warmup {
+            status!("[ralph] warmup iteration (not logged)");
+        }
+        let iteration_log = (!args.no_log && !warmup).then_some(log_path.as_path());
+        run_repo_hook(
+            &cwd,
+            "pre-iteration",
+            &[("RALPH_ITERATION", i.to_string())],
+            iteration_log,
+            args.redact_log.as_deref(),
+        );
+        let mut iteration_prompt = with_history(&prompt, &history);
+        if i == 1
+            && let Some(review) = previous_run.as_deref()
+        {
+            iteration_prompt = format!("# Previous run outcome\n\n{review}\n\n{iteration_prompt}");
+        }
+        if let Some(context) = recovered_context.take() {
+            iteration_prompt.push_str(&format!(
+                "\n\n# Repository context after merge-conflict recovery\n\n{}\n",
+                truncate_string(&context, 20000)
+            ));
+        }
+        if args.prompt_context_env
+            && let Some(env) =
+                env_context(&args.context_env, args.redact_log.as_deref().unwrap_or_default())
+        {
+            iteration_prompt.push_str(&format!("\n\n# Environment\n\n{env}\n"));
+        }
+        if args.plan_in_prompt {
+            iteration_prompt.push_str(&format!(
+                "\n\nFollow the plan in @{} and keep it up to date.\n",
+                plan_path.display()
+            ));
+        }
+        if args.prompt_max_files_attached > 0 {
+            let priority = [prd_path.as_path(), progress_path.as_path(), plan_path.as_path()];
+            let (capped, dropped) =
+                cap_file_refs(&iteration_prompt, args.prompt_max_files_attached, &priority);
+            if !dropped.is_empty() {
+                eprintln!(
+                    "[ralph] warning: prompt references more than {} files; not attaching {}",
+                    args.prompt_max_files_attached,
+                    dropped.join(", ")
+                );
+                iteration_prompt = capped;
+            }
+        }
+        if args.prompt_preview_lines > 0 {
+            print_prompt_preview(&iteration_prompt, args.prompt_preview_lines);
+        }
+        let scheduled = schedule.get(&i);
+        let scheduled_model = scheduled.and_then(|entry| entry.model.as_deref());
+        let iteration_model = if let Some(picked) = scheduled_model {
+            status!("[ralph] scheduled model {picked}");
+            picked
+        } else if args.models.is_empty() {
+            model.as_str()
+        } else {
+            let picked = args.models[(i as usize - 1) % args.models.len()].trim();
+            status!("[ralph] model {picked}");
+            picked
+        };
+        let iteration_effort = scheduled
+            .and_then(|entry| entry.reasoning_effort.as_deref())
+            .unwrap_or(&reasoning_effort);
+        let iteration_runner_args = scheduled
+            .and_then(|entry| entry.runner_arg.as_deref())
+            .unwrap_or(&args.runner_arg);
+        emit_event(
+            &mut events,
+            "iteration_start",
+            serde_json::json!({ "iteration": i, "model": iteration_model }),
+        );
+        let state_before: Vec<Option<u64>> = state_files.iter().map(|path| file_hash(path)).collect();
+        let protected_template = (args.protect_template && uses_template)
+            .then(|| std::fs::read(&prompt_template).ok())
+            .flatten();
+        let iteration_start = Instant::now();
+        let mut attempt = 0;
+        let output = loop {
+            #[cfg(feature = "mock-runner")]
+            let mock = (runner == "mock").then(|| run_mock(i, args.mock_script.as_deref()));
+            #[cfg(not(feature = "mock-runner"))]
+            let mock: Option<io::Result<Output>> = None;
+            let result = if let Some(result) = mock {
+                result
             } else {
-                if (args.resume || args.resume_id.is_some()) && runner != "codex" {
+                if (args.resume || resume_id.is_some()) && runner != "codex" {
                     eprintln!("[ralph] resume requested but runner is not codex; ignoring resume.");
                 }
-                run_generic(
-                    &runner,
-                    &model,
-                    &prompt_flag,
-                    &prompt,
-                    &args.runner_arg,
-                    yolo,
-                    runner_timeout,
-                )
+                let call = Invocation {
+                    prompt: &iteration_prompt,
+                    model: iteration_model,
+                    effort: iteration_effort,
+                    runner_args: iteration_runner_args,
+                    resume_last: args.resume,
+                    resume_id: resume_id.as_deref(),
+                };
+                run_agent(&call, &runner_options, Some(&mut codex_events))
             };
-            match result {
+            let output = match result {
                 Ok(output) => output,
-                Err(err) => {
-                    if err.kind() == io::ErrorKind::TimedOut {
-                        stop_reason = Some("runner timed out".to_string());
-                        break;
-                    } else {
-                        return Err(err);
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                    if attempt < args.max_retries {
+                        attempt += 1;
+                        eprintln!(
+                            "[ralph] runner timed out; retrying ({attempt}/{})",
+                            args.max_retries
+                        );
+                        add_retry_note(&mut iteration_prompt, attempt, &args.retry_note);
+                        continue;
                     }
+                    stop_evaluation.record("runner timeout", "timed out".to_string(), true);
+                    stop_reason = Some("runner timed out".to_string());
+                    break 'iterations;
+                }
+                Err(err) => return Err(err),
+            };
+            let empty = args.fail_on_empty_output && output.stdout.trim_ascii().is_empty();
+            // Only failures count: codex echoes its transcript on stderr, which can mention
+            // rate limits in code the agent merely read.
+            let rate_limited = args.rate_limit_cooldown > 0
+                && !output.status.success()
+                && looks_rate_limited(&output.stderr);
+            if rate_limited {
+                rate_limit_hits += 1;
+            } else {
+                rate_limit_hits = 0;
+            }
+            let retryable = !output.status.success() || empty;
+            if retryable && attempt < args.max_retries {
+                attempt += 1;
+                let reason = if rate_limited {
+                    "runner was rate limited".to_string()
+                } else if empty {
+                    "runner produced no output".to_string()
+                } else {
+                    runner_failure_reason(&output.status)
+                };
+                eprintln!("[ralph] {reason}; retrying ({attempt}/{})", args.max_retries);
+                add_retry_note(&mut iteration_prompt, attempt, &args.retry_note);
+                let cooldown = rate_limit_backoff(args.rate_limit_cooldown, rate_limit_hits);
+                if cooldown > 0 {
+                    status!(
+                        "[ralph] rate limited {rate_limit_hits} times in a row; cooling down {cooldown}s"
+                    );
+                    std::thread::sleep(Duration::from_secs(cooldown));
                 }
+                continue;
             }
+            break output;
         };
 
         let stdout = output.stdout;
         let stderr = output.stderr;
+        let shown_stdout = if args.stop_token_strip
+            && let Some(token) = find_stop_token(&stdout, &stop_tokens)
+        {
+            strip_stop_token(&stdout, token, args.stop_token_strip_trailing)
+        } else {
+            stdout.clone()
+        };
 
-        if !stdout.is_empty() {
-            io::stdout().write_all(&stdout)?;
+        if !shown_stdout.is_empty() && !args.dump_last_message {
+            io::stdout().write_all(&shown_stdout)?;
         }
         if !stderr.is_empty() {
             io::stderr().write_all(&stderr)?;
         }
 
-        if !args.no_log {
-            append_log(&log_path, i, &stdout, &stderr, &output.status)?;
+        let iteration_secs = iteration_start.elapsed().as_secs();
+        let slow_secs = (args.warn_slow_iteration > 0
+            && !warmup
+            && iteration_secs >= args.warn_slow_iteration)
+            .then_some(iteration_secs);
+        if slow_secs.is_some() {
+            eprintln!(
+                "[ralph] warning: iteration {i} took {iteration_secs}s (slow threshold {}s)",
+                args.warn_slow_iteration
+            );
+        }
+        if let Some(log_path) = iteration_log {
+            append_log(
+                log_path,
+                i,
+                &shown_stdout,
+                &stderr,
+                &output.status,
+                slow_secs,
+                args.redact_log.as_deref(),
+            )?;
+        }
+        iterations_run = i;
+        last_message.clone_from(&shown_stdout);
+        if let Some(path) = args.supervise_state.as_deref()
+            && let Err(err) = std::fs::write(path, format!("{i} {}", codex_events.tokens))
+        {
+            eprintln!("[ralph] could not record supervisor state: {err}");
+        }
+        if !args.keep_temp {
+            remove_temp_files();
         }
 
-        if !output.status.success() {
-            let code = output.status.code().unwrap_or(1);
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Runner exited with code {code}"),
-            ));
+        emit_event(
+            &mut events,
+            "iteration_end",
+            serde_json::json!({
+                "iteration": i,
+                "exit_code": output.status.code(),
+                "duration_ms": iteration_start.elapsed().as_millis() as u64,
+            }),
+        );
+        if args.progress_format == ProgressFormat::Jsonl && !warmup {
+            let status = if output.status.success() { "ok" } else { "failed" };
+            let note = format!("iteration took {}s", iteration_start.elapsed().as_secs());
+            if let Err(err) = append_progress_entry(&progress_path, i, status, &note) {
+                eprintln!("[ralph] could not append progress entry: {err}");
+            }
+        }
+        run_repo_hook(
+            &cwd,
+            "post-iteration",
+            &[
+                ("RALPH_ITERATION", i.to_string()),
+                ("RALPH_EXIT_CODE", output.status.code().unwrap_or(-1).to_string()),
+            ],
+            iteration_log,
+            args.redact_log.as_deref(),
+        );
+        for (path, before) in state_files.iter().zip(&state_before) {
+            if file_hash(path) != *before {
+                if args.guard_state {
+                    eprintln!("[ralph] warning: agent modified {}", path.display());
+                } else {
+                    status!("[ralph] state file changed: {}", path.display());
+                }
+            }
+        }
+        if let Some(original) = protected_template.as_deref()
+            && std::fs::read(&prompt_template).ok().as_deref() != Some(original)
+        {
+            match std::fs::write(&prompt_template, original) {
+                Ok(()) => eprintln!(
+                    "[ralph] restored protected template {}",
+                    prompt_template.display()
+                ),
+                Err(err) => eprintln!("[ralph] could not restore protected template: {err}"),
+            }
+        }
+
+        let empty_output = output.status.success() && stdout.trim_ascii().is_empty();
+        if args.fail_on_empty_output
+            && stop_evaluation.record("empty output", empty_output.to_string(), empty_output)
+        {
+            let reason = "runner produced no output".to_string();
+            run_error = Some(io::Error::other(format!("Runner failed: {reason}")));
+            stop_reason = Some(reason);
+            exit_code = 1;
+            break;
+        }
+        let runner_status = if output.status.success() {
+            "success".to_string()
+        } else {
+            runner_failure_reason(&output.status)
+        };
+        if stop_evaluation.record("runner exit", runner_status, !output.status.success()) {
+            let reason = runner_failure_reason(&output.status);
+            run_error = Some(io::Error::other(format!("Runner failed: {reason}")));
+            stop_reason = Some(reason);
+            exit_code = 1;
+            break;
+        }
+
+        if let Some(token) = blocked_token.as_deref()
+            && stop_evaluation.record(
+                "blocked token",
+                format!("{token} {}", seen_label(contains_stop_token(&stdout, token))),
+                contains_stop_token(&stdout, token),
+            )
+        {
+            stop_reason = Some("blocked token detected".to_string());
+            exit_code = 2;
+            break;
+        }
+        if args.max_prompt_history > 0 {
+            let message = String::from_utf8_lossy(&stdout).trim().to_string();
+            if !message.is_empty() {
+                history.push_back((i, truncate_string(&message, 2000)));
+                while history.len() > args.max_prompt_history {
+                    history.pop_front();
+                }
+            }
+        }
+
+        let token_seen = match args.stop_token_scan {
+            StopTokenScan::Stdout => find_stop_token(&stdout, &stop_tokens).is_some(),
+            StopTokenScan::Stderr => find_stop_token(&stderr, &stop_tokens).is_some(),
+            StopTokenScan::Both => {
+                find_stop_token(&stdout, &stop_tokens).is_some()
+                    || find_stop_token(&stderr, &stop_tokens).is_some()
+            }
+        } || std::mem::take(&mut codex_events.stop_seen);
+        token_fired |= token_seen;
+        let token_stops = stop_when.is_none() || args.stop_after_first_success;
+        if stop_evaluation.record(
+            "stop token",
+            seen_label(token_seen).to_string(),
+            token_seen && token_stops,
+        ) {
+            stop_reason = Some(if args.stop_after_first_success {
+                format!("completion token detected (model {iteration_model})")
+            } else {
+                "completion token detected".to_string()
+            });
+            break;
+        }
+        if let Some(condition) = stop_when.as_ref() {
+            if let Some(previous) = last_fingerprint.as_mut() {
+                let current = worktree_fingerprint(&cwd);
+                if current == *previous {
+                    idle_streak += 1;
+                } else {
+                    idle_streak = 0;
+                    *previous = current;
+                }
+            }
+            let state = StopState {
+                token_seen,
+                iterations: i,
+                elapsed: start.elapsed(),
+                idle_streak,
+                cwd: &cwd,
+            };
+            let expr = args.stop_when.as_deref().unwrap_or_default();
+            let met = condition.evaluate(&state);
+            let value = format!("{expr} (idle streak {idle_streak})");
+            if stop_evaluation.record("stop-when", value, met) {
+                stop_reason = Some(format!("stop condition met ({expr})"));
+                break;
+            }
         }
 
-        let stdout_text = String::from_utf8_lossy(&stdout);
-        if stdout_text.contains(&stop_token) {
-            stop_reason = Some("completion token detected".to_string());
+        if (args.recover_on_conflict || args.recover_command.is_some()) && has_merge_conflict(&cwd)
+        {
+            let command = args.recover_command.as_deref().unwrap_or("git merge --abort");
+            status!("[ralph] merge conflict detected; running recovery: {command}");
+            match run_shell_command(command, &cwd) {
+                Ok(recovery) => {
+                    io::stdout().write_all(&recovery.stdout)?;
+                    io::stderr().write_all(&recovery.stderr)?;
+                    if !recovery.status.success() {
+                        eprintln!(
+                            "[ralph] recovery command exited with {:?}",
+                            recovery.status.code()
+                        );
+                    }
+                }
+                Err(err) => eprintln!("[ralph] recovery command failed to start: {err}"),
+            }
+            if has_merge_conflict(&cwd) {
+                eprintln!("[ralph] conflict still present after recovery.");
+            }
+            if uses_template {
+                prompt = with_extra(
+                    load_prompt(&prompt_template, prd_ref, progress_ref, args.prompt_debug)?,
+                    args.extra.as_deref(),
+                );
+            }
+            match prepare_inference_context(
+                repo_name,
+                &cwd,
+                context_log.as_deref(),
+                None,
+                &context_options,
+            ) {
+                Ok(context) => recovered_context = Some(context),
+                Err(err) => eprintln!("[ralph] could not refresh context after recovery: {err}"),
+            }
+        }
+
+        if args.max_total_tokens > 0
+            && stop_evaluation.record(
+                "token budget",
+                format!("{} of {}", codex_events.tokens, args.max_total_tokens),
+                codex_events.tokens >= args.max_total_tokens,
+            )
+        {
+            stop_reason = Some("token budget reached".to_string());
+            break;
+        }
+
+        if let Some(files) = task_files.as_ref()
+            && stop_evaluation.record(
+                "task directory",
+                format!("{i} of {} tasks", files.len()),
+                i as usize >= files.len(),
+            )
+        {
+            stop_reason = Some("task directory exhausted".to_string());
             break;
         }
 
         if i < iterations {
-            println!("[ralph] sleeping {sleep_secs}s before next iteration");
+            let sleep_secs = if args.adaptive_sleep {
+                sleep_secs.saturating_sub(iteration_start.elapsed().as_secs())
+            } else {
+                sleep_secs
+            };
+            let sleep_secs = sleep_secs + rng.up_to(args.sleep_jitter);
+            let cooldown = rate_limit_backoff(args.rate_limit_cooldown, rate_limit_hits);
+            let sleep_secs = if cooldown > sleep_secs {
+                status!(
+                    "[ralph] rate limited {rate_limit_hits} times in a row; cooling down {cooldown}s"
+                );
+                cooldown
+            } else {
+                sleep_secs
+            };
+            status!("[ralph] sleeping {sleep_secs}s before next iteration");
             std::thread::sleep(std::time::Duration::from_secs(sleep_secs));
         } else {
+            stop_evaluation.record("max iterations", format!("{i} of {iterations}"), true);
             stop_reason = Some("reached max iterations".to_string());
         }
     }
 
-    if let Some(reason) = stop_reason {
-        println!("[ralph] stop: {reason}.");
+    if args.require_stop_token
+        && stop_evaluation.record(
+            "require stop token",
+            seen_label(token_fired).to_string(),
+            !token_fired && exit_code == 0,
+        )
+    {
+        let ended = stop_reason.as_deref().unwrap_or("loop ended");
+        stop_reason = Some(format!("stop token never appeared ({ended})"));
+        exit_code = 3;
+    }
+    if let Some(reason) = stop_reason.as_deref() {
+        status!("[ralph] stop: {reason}.");
+        for line in stop_evaluation.lines() {
+            status!("[ralph]   {line}");
+        }
     }
+    emit_event(
+        &mut events,
+        "stop",
+        serde_json::json!({ "reason": stop_reason, "exit_code": exit_code }),
+    );
 
-    Ok(())
+    let summary = RunSummary {
+        repo: repo_name.to_string(),
+        runner: runner.clone(),
+        model: model.clone(),
+        started_at,
+        duration_secs: start.elapsed().as_secs(),
+        iterations: iterations_run,
+        stop_reason: stop_reason.clone(),
+        exit_code,
+        start_head,
+        tokens: codex_events.tokens,
+        tool_calls: codex_events.tool_calls,
+        baseline,
+        stop_evaluation,
+        seed,
+    };
+    // Saved even under --no-log: it is run state for --resume-context, not a log.
+    if let Err(err) = write_run_summary(&cwd, &summary) {
+        eprintln!("[ralph] could not save run summary: {err}");
+    }
+    if args.summary_format == SummaryFormat::Markdown {
+        match write_run_summary_markdown(&cwd, &summary) {
+            Ok(path) => status!("[ralph] wrote run summary to {}", path.display()),
+            Err(err) => eprintln!("[ralph] could not write markdown run summary: {err}"),
+        }
+    }
+    if args.append_run_summary
+        && let Err(err) = append_run_summary(&progress_path, &summary, args.progress_format)
+    {
+        eprintln!("[ralph] could not append run summary to progress log: {err}");
+    }
+    if let Some(target) = args.summary_to_linear.as_deref() {
+        let review = render_run_review(&summary, &cwd);
+        match post_linear_summary(&context_options.linear, target, &review) {
+            Ok(()) => status!("[ralph] posted run review to Linear ({target})"),
+            Err(err) => eprintln!("[ralph] could not post run review to Linear: {err}"),
+        }
+    }
+
+    run_repo_hook(
+        &cwd,
+        "on-stop",
+        &[
+            ("RALPH_ITERATION", iterations_run.to_string()),
+            ("RALPH_STOP_REASON", stop_reason.clone().unwrap_or_default()),
+            ("RALPH_EXIT_CODE", exit_code.to_string()),
+        ],
+        (!args.no_log).then_some(log_path.as_path()),
+        args.redact_log.as_deref(),
+    );
+    if let Some(command) = args.post_run_command.as_deref()
+        && (exit_code == 0 || args.post_run_always)
+    {
+        run_post_run_command(
+            command,
+            &cwd,
+            stop_reason.as_deref().unwrap_or(""),
+            exit_code,
+            (!args.no_log).then_some(log_path.as_path()),
+            args.redact_log.as_deref(),
+        );
+    }
+
+    if args.dump_last_message && !last_message.trim_ascii().is_empty() {
+        io::stdout().write_all(last_message.trim_ascii_end())?;
+        println!();
+    }
+    if let Some(err) = run_error {
+        return Err(err);
+    }
+    Ok(exit_code)
 }
 
 mod which {
@@ -1546,3 +6146,263 @@ mod which {
         path.is_file()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stop_token_found_among_invalid_utf8() {
+        assert!(contains_stop_token(b"\xff\xfe__RALPH_DONE__\xc3", "__RALPH_DONE__"));
+    }
+
+    #[test]
+    fn stop_token_absent_or_split_by_invalid_utf8() {
+        assert!(!contains_stop_token(b"\xff\xfe__RALPH_\xc3DONE__", "__RALPH_DONE__"));
+        assert!(!contains_stop_token(b"__RALPH_DONE__", ""));
+    }
+
+    #[test]
+    fn url_passwords_are_masked() {
+        assert_eq!(
+            mask_url_userinfo("postgres://u:pw@db:5432/app?x=a@b"),
+            "postgres://u:***@db:5432/app?x=a@b"
+        );
+        assert_eq!(mask_url_userinfo("https://u@host/p"), "https://u@host/p");
+        assert_eq!(mask_url_userinfo("staging"), "staging");
+    }
+
+    #[test]
+    fn redaction_masks_prefixed_tokens_and_glob_words() {
+        let redact = |input: &str, patterns: &[&str]| {
+            let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+            String::from_utf8(redact_secrets(input.as_bytes(), &patterns)).unwrap()
+        };
+        assert_eq!(redact("key=sk-abcdefgh12 sk-short", &[]), "key=[REDACTED] sk-short");
+        assert_eq!(redact("use acme_0123456789.", &["acme_"]), "use [REDACTED].");
+        assert_eq!(
+            redact("login password=hunter2\tPASSWORD=x", &["password=*"]),
+            "login [REDACTED]\tPASSWORD=x"
+        );
+        assert_eq!(redact("pin 1234 12345", &["pin", "????"]), "pin [REDACTED] 12345");
+        assert_eq!(
+            redact("url https://u:pw@db/app db/app", &["*://*:*@*"]),
+            "url [REDACTED] db/app"
+        );
+        assert!(glob_matches(b"a*b*c", b"aXbYbc"));
+        assert!(!glob_matches(b"a*b", b"aXbY"));
+        assert!(glob_matches(b"**", b""));
+    }
+
+    #[test]
+    fn durations_accept_seconds_and_unit_sequences() {
+        assert_eq!(parse_duration_secs("90"), Ok(90));
+        assert_eq!(parse_duration_secs(" 0 "), Ok(0));
+        assert_eq!(parse_duration_secs("45s"), Ok(45));
+        assert_eq!(parse_duration_secs("1h30m"), Ok(90 * 60));
+        assert_eq!(parse_duration_secs("1d2h3m4s"), Ok(93_784));
+        assert_eq!(parse_runner_timeout("None"), Ok(0));
+    }
+
+    #[test]
+    fn durations_reject_malformed_and_overflowing_values() {
+        for value in ["", "m", "h30m", "5w", "1h 30m", "1h30", "-5", "1.5h"] {
+            assert!(parse_duration_secs(value).is_err(), "{value} should be rejected");
+        }
+        assert!(parse_duration_secs("99999999999999999999s").is_err());
+        assert!(parse_duration_secs("300000000000000000d").is_err());
+        assert!(parse_duration_secs(&format!("{}s1s", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn stop_expression_and_binds_tighter_than_or() {
+        use StopCondition::*;
+        assert_eq!(
+            parse_stop_expression("token or iterations:3 and idle:2").unwrap(),
+            Or(vec![Token, And(vec![Iterations(3), Idle(2)])])
+        );
+        assert_eq!(
+            parse_stop_expression("(token OR iterations:3) and file:ralph/DONE").unwrap(),
+            And(vec![Or(vec![Token, Iterations(3)]), File(PathBuf::from("ralph/DONE"))])
+        );
+        assert_eq!(parse_stop_expression("((seconds:60))").unwrap(), Seconds(60));
+        assert!(parse_stop_expression("(token or idle:2").unwrap_err().contains("missing `)`"));
+        assert!(parse_stop_expression("token idle:2").unwrap_err().contains("unexpected"));
+        assert!(parse_stop_expression("token and").is_err());
+    }
+
+    #[test]
+    fn stop_primitives_reject_unknown_names_cost_and_bad_values() {
+        assert!(parse_stop_primitive("tokens").unwrap_err().contains("unknown stop condition"));
+        assert!(parse_stop_primitive("cost:5").unwrap_err().contains("not supported"));
+        assert!(parse_stop_primitive("iterations").unwrap_err().contains("needs a value"));
+        assert!(parse_stop_primitive("idle:x").unwrap_err().contains("invalid number"));
+        assert!(parse_stop_primitive("idle:5000000000").unwrap_err().contains("too large"));
+        assert!(parse_stop_primitive("file:").is_err());
+    }
+
+    #[test]
+    fn stop_expression_evaluates_against_state() {
+        let condition = parse_stop_expression("token or iterations:3 and idle:2").unwrap();
+        let cwd = env::temp_dir();
+        let state = |token_seen, iterations, idle_streak| StopState {
+            token_seen,
+            iterations,
+            elapsed: Duration::from_secs(0),
+            idle_streak,
+            cwd: &cwd,
+        };
+        assert!(condition.evaluate(&state(true, 0, 0)));
+        assert!(condition.evaluate(&state(false, 3, 2)));
+        assert!(!condition.evaluate(&state(false, 3, 1)));
+        assert!(!condition.evaluate(&state(false, 2, 5)));
+        assert!(condition.uses_idle());
+
+        let missing = format!("ralph-no-such-file-{}", std::process::id());
+        let file = parse_stop_expression(&format!("file:{missing} or seconds:10")).unwrap();
+        let mut after = state(false, 0, 0);
+        assert!(!file.evaluate(&after));
+        after.elapsed = Duration::from_secs(10);
+        assert!(file.evaluate(&after));
+        assert!(!file.uses_idle());
+    }
+
+    #[test]
+    fn config_file_becomes_flags_overridden_by_command_line() {
+        let flags = config_file_args(
+            "# ralph.toml\nonce = true\nno_log = false\nsleep = \"30m\" # half an hour\n\
+             runner_arg = [\"--a\", 'b c']\ngoal = \"say \\\"hi\\\"\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            flags,
+            ["--once", "--sleep=30m", "--runner-arg=--a", "--runner-arg=b c", "--goal=say \"hi\""]
+                .map(OsString::from)
+        );
+        assert!(config_file_args("supervise_state = \"x\"").unwrap_err().contains("unknown key"));
+        assert!(config_file_args("once = \"yes\"").is_err());
+        assert!(config_file_args("[loop]").is_err());
+
+        let dir = env::temp_dir().join(format!("ralph-config-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let path = dir.join("ralph.toml");
+        std::fs::write(&path, "iterations = 3\nsleep = 5\n").unwrap();
+        let argv = ["ralph", "--config", path.to_str().unwrap(), "--sleep", "1"];
+        let args = parse_args(argv.map(OsString::from).to_vec()).unwrap();
+        assert_eq!((args.iterations, args.sleep), (3, 1));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn config_schema_types_follow_value_parsers() {
+        let schema = config_schema();
+        let properties = &schema["properties"];
+        assert_eq!(properties["sleep"]["type"], serde_json::json!(["integer", "string"]));
+        assert_eq!(properties["seed"]["type"], "integer");
+        assert_eq!(properties["once"]["type"], "boolean");
+        assert!(properties.get("supervise_state").is_none());
+        assert!(properties.get("config").is_none());
+    }
+
+    #[cfg(feature = "mock-runner")]
+    fn run_mock_loop(name: &str, script: &str) -> (io::Result<i32>, PathBuf) {
+        let dir = env::temp_dir().join(format!("ralph-mock-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        let script_path = dir.join("script.txt");
+        std::fs::write(&script_path, script).unwrap();
+        let args = Args::try_parse_from([
+            "ralph",
+            "--runner",
+            "mock",
+            "--mock-script",
+            script_path.to_str().unwrap(),
+            "--goal",
+            "test the loop",
+            "--next-action",
+            "run",
+            "--no-ensure-mcp",
+            "--iterations",
+            "5",
+            "--sleep",
+            "0",
+        ])
+        .unwrap();
+        (run(args, dir.clone()), dir)
+    }
+
+    #[cfg(feature = "mock-runner")]
+    #[test]
+    fn mock_loop_stops_on_token_in_second_iteration() {
+        let (result, dir) = run_mock_loop("token", "working\n---\ndone __RALPH_DONE__\n");
+        assert_eq!(result.unwrap(), 0);
+        let summary: RunSummary =
+            serde_json::from_str(&std::fs::read_to_string(dir.join("ralph/last-run.json")).unwrap())
+                .unwrap();
+        assert_eq!(summary.iterations, 2);
+        assert_eq!(summary.stop_reason.as_deref(), Some("completion token detected"));
+        let log = std::fs::read_to_string(dir.join("ralph/overnight.log")).unwrap();
+        assert_eq!(parse_run_log(&log).len(), 2);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[cfg(feature = "mock-runner")]
+    #[test]
+    fn supervisor_restart_continues_iteration_numbering() {
+        let dir = env::temp_dir().join(format!("ralph-mock-supervise-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        let script_path = dir.join("script.txt");
+        let script = "one\n---\nexit: 1\nboom\n---\nthree __RALPH_DONE__\n";
+        std::fs::write(&script_path, script).unwrap();
+        let argv: Vec<std::ffi::OsString> = [
+            "--supervise",
+            "--runner=mock",
+            "--mock-script",
+            script_path.to_str().unwrap(),
+            "--goal",
+            "test the supervisor",
+            "--next-action",
+            "run",
+            "--no-ensure-mcp",
+            "--iterations=5",
+            "--sleep",
+            "0",
+        ]
+        .map(Into::into)
+        .to_vec();
+        let parent = Args::try_parse_from(std::iter::once("ralph".into()).chain(argv.clone()))
+            .unwrap();
+        let mut spawned = Vec::new();
+        let code = supervise_with(&parent, argv.into_iter(), |child_args| {
+            let child =
+                Args::try_parse_from(std::iter::once("ralph".into()).chain(child_args.to_vec()))
+                    .unwrap();
+            spawned.push((child.start_iteration, child.iterations, child.supervise));
+            let _ = run(child, dir.clone());
+            // The first child "crashes" after the failing second iteration.
+            Ok(ExitStatus::from_raw(if spawned.len() == 1 { 9 } else { 0 }))
+        })
+        .unwrap();
+        assert_eq!(code, 0);
+        assert_eq!(spawned, [(1, 5, false), (3, 5, false)]);
+        let summary: RunSummary =
+            serde_json::from_str(&std::fs::read_to_string(dir.join("ralph/last-run.json")).unwrap())
+                .unwrap();
+        assert_eq!(summary.iterations, 3);
+        assert_eq!(summary.stop_reason.as_deref(), Some("completion token detected"));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[cfg(feature = "mock-runner")]
+    #[test]
+    fn mock_loop_fails_on_nonzero_exit() {
+        let (result, dir) = run_mock_loop("exit", "exit: 300\nboom\n");
+        assert!(result.is_err());
+        let log = std::fs::read_to_string(dir.join("ralph/overnight.log")).unwrap();
+        let records = parse_run_log(&log);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].exit_code, Some(255));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}